@@ -0,0 +1,255 @@
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::PhysAddr;
+
+// Just enough ACPI to find the firmware's tables and read the ones we need,
+// there is no AML interpreter here. The tables live in ordinary RAM, so they
+// are read through the bootloader's physical memory mapping.
+
+static ROOT_TABLE: Once<RootTable> = Once::new();
+
+// The RSDT lists its tables with 32 bit addresses, the XSDT (ACPI 2.0 and
+// later) with 64 bit ones, otherwise they are the same.
+struct RootTable {
+    address: PhysAddr,
+    entry_size: u64,
+}
+
+/// The header every system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: u64 = core::mem::size_of::<SdtHeader>() as u64;
+
+/// Looks for the RSDP and records where the root table is. When there is no
+/// ACPI (QEMU with `-no-acpi`), `find_table` simply finds nothing.
+pub fn init() {
+    if let Some(rsdp) = find_rsdp() {
+        ROOT_TABLE.call_once(|| rsdp);
+    }
+}
+
+pub fn is_available() -> bool {
+    ROOT_TABLE.wait().is_some()
+}
+
+/// The physical address of the table with the given signature, like `b"APIC"`,
+/// if the firmware provides one with a valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let root = ROOT_TABLE.wait()?;
+    let header: SdtHeader = unsafe { read_phys(root.address) };
+    let count = (u64::from(header.length) - SDT_HEADER_SIZE) / root.entry_size;
+    (0..count)
+        .map(|index| {
+            let entry = root.address + SDT_HEADER_SIZE + index * root.entry_size;
+            if root.entry_size == 8 {
+                PhysAddr::new(unsafe { read_phys::<u64>(entry) })
+            } else {
+                PhysAddr::new(u64::from(unsafe { read_phys::<u32>(entry) }))
+            }
+        })
+        .find(|&table| {
+            let header: SdtHeader = unsafe { read_phys(table) };
+            header.signature == *signature && checksum_ok(table, u64::from(header.length))
+        })
+}
+
+/// Reads a `T` from physical memory, tables are packed so this copes with
+/// any alignment.
+///
+/// Unsafe because `addr` must point at `T`-sized ACPI data.
+pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    core::ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
+
+fn checksum_ok(addr: PhysAddr, length: u64) -> bool {
+    (0..length)
+        .map(|offset| unsafe { read_phys::<u8>(addr + offset) })
+        .fold(0u8, |sum, byte| sum.wrapping_add(byte))
+        == 0
+}
+
+fn find_rsdp() -> Option<RootTable> {
+    // The RSDP is 16 byte aligned, either in the first KiB of the extended
+    // BIOS data area or in the BIOS ROM between 0xe0000 and 0xfffff.
+    let ebda = u64::from(unsafe { read_phys::<u16>(PhysAddr::new(0x40e)) }) << 4;
+    let ebda_range = (ebda..ebda + 1024).step_by(16);
+    let bios_range = (0xe_0000..0x10_0000).step_by(16);
+    ebda_range
+        .chain(bios_range)
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            let signature: [u8; 8] = unsafe { read_phys(addr) };
+            signature == *b"RSD PTR " && checksum_ok(addr, 20)
+        })
+        .map(|rsdp| {
+            let revision: u8 = unsafe { read_phys(rsdp + 15u64) };
+            let xsdt: u64 = unsafe { read_phys(rsdp + 24u64) };
+            if revision >= 2 && xsdt != 0 && checksum_ok(rsdp, 36) {
+                RootTable { address: PhysAddr::new(xsdt), entry_size: 8 }
+            } else {
+                let rsdt: u32 = unsafe { read_phys(rsdp + 16u64) };
+                RootTable { address: PhysAddr::new(u64::from(rsdt)), entry_size: 4 }
+            }
+        })
+}
+
+/// A CPU core as listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// Where an ISA IRQ really arrives at the I/O APICs, and how it signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The parts of the MADT (signature `APIC`) we use: the local APIC, the I/O
+/// APICs, and how ISA IRQs are wired to them.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether the machine also has the legacy 8259 PICs.
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// How ISA IRQ `irq` is routed. Without an override it's identity mapped,
+    /// edge triggered and active high, like on the ISA bus, unless another
+    /// IRQ was moved onto that GSI. Then `irq` isn't wired to anything and
+    /// this is `None`: the PIT's IRQ 0 usually arrives on GSI 2, where the
+    /// PICs' cascade IRQ 2 would be.
+    pub fn isa_route(&self, irq: u8) -> Option<InterruptOverride> {
+        if let Some(route) = self.overrides.iter().find(|route| route.isa_irq == irq) {
+            return Some(*route);
+        }
+        let gsi = u32::from(irq);
+        if self.overrides.iter().any(|route| route.gsi == gsi) {
+            return None;
+        }
+        Some(InterruptOverride {
+            isa_irq: irq,
+            gsi,
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+}
+
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let header: SdtHeader = unsafe { read_phys(table) };
+    let end = table + u64::from(header.length);
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(unsafe { read_phys::<u32>(table + 36u64) })),
+        has_8259: unsafe { read_phys::<u32>(table + 40u64) } & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    // The rest of the table is a list of variable length entries, each
+    // starting with its type and length.
+    let mut entry = table + 44u64;
+    while entry + 2u64 <= end {
+        let (kind, length): (u8, u8) = unsafe { (read_phys(entry), read_phys(entry + 1u64)) };
+        if length < 2 {
+            break; // a broken table, don't loop forever
+        }
+        unsafe {
+            match kind {
+                0 => madt.processors.push(Processor {
+                    processor_id: read_phys(entry + 2u64),
+                    apic_id: read_phys(entry + 3u64),
+                    enabled: read_phys::<u32>(entry + 4u64) & 1 != 0,
+                }),
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: read_phys(entry + 2u64),
+                    address: PhysAddr::new(u64::from(read_phys::<u32>(entry + 4u64))),
+                    gsi_base: read_phys(entry + 8u64),
+                }),
+                2 => {
+                    // Polarity and trigger mode, where 0b00 means "whatever
+                    // the bus does", which for ISA is active high and edge.
+                    let flags: u16 = read_phys(entry + 8u64);
+                    madt.overrides.push(InterruptOverride {
+                        isa_irq: read_phys(entry + 3u64),
+                        gsi: read_phys(entry + 4u64),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+                5 => madt.local_apic_address = PhysAddr::new(read_phys(entry + 4u64)),
+                _ => {}
+            }
+        }
+        entry += u64::from(length);
+    }
+    Some(madt)
+}
+
+#[test_case]
+fn test_madt_lists_processors_and_io_apics() {
+    // QEMU always has ACPI unless started with `-no-acpi`.
+    if let Some(madt) = madt() {
+        assert!(madt.processors.iter().any(|processor| processor.enabled));
+        assert!(!madt.io_apics.is_empty());
+        assert_eq!(madt.isa_route(1).map(|route| route.gsi), Some(1));
+    }
+}
+
+#[test_case]
+fn test_isa_route_follows_overrides() {
+    let madt = Madt {
+        local_apic_address: PhysAddr::new(0xfee0_0000),
+        has_8259: true,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: alloc::vec![InterruptOverride {
+            isa_irq: 0,
+            gsi: 2,
+            active_low: false,
+            level_triggered: false,
+        }],
+    };
+    assert_eq!(madt.isa_route(0).map(|route| route.gsi), Some(2));
+    assert_eq!(madt.isa_route(1).map(|route| route.gsi), Some(1));
+    // IRQ 2 would land on the timer's GSI.
+    assert_eq!(madt.isa_route(2), None);
+}
+
+#[test_case]
+fn test_missing_table_is_not_found() {
+    assert_eq!(find_table(b"NONE"), None);
+}
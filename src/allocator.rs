@@ -0,0 +1,276 @@
+use crate::memory::paging::{kernel_page_table, PagingError};
+use crate::{println, serial_println};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+
+// The heap lives at a fixed, otherwise unused, spot in the virtual address
+// space, backed by frames from the frame allocator when `init_heap` runs.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+
+// Which design backs the heap is picked with cargo features, see Cargo.toml.
+// If more than one is enabled the first one here wins, so
+// `--features bump-allocator` works without `--no-default-features`.
+#[cfg(feature = "bump-allocator")]
+type KernelAllocator = bump::BumpAllocator;
+#[cfg(all(feature = "linked-list-allocator", not(feature = "bump-allocator")))]
+type KernelAllocator = linked_list::LinkedListAllocator;
+#[cfg(all(
+    feature = "fixed-size-block-allocator",
+    not(any(feature = "bump-allocator", feature = "linked-list-allocator"))
+))]
+type KernelAllocator = fixed_size_block::FixedSizeBlockAllocator;
+
+#[cfg(not(any(
+    feature = "bump-allocator",
+    feature = "linked-list-allocator",
+    feature = "fixed-size-block-allocator"
+)))]
+compile_error!("enable one of the bump-allocator, linked-list-allocator or fixed-size-block-allocator features");
+
+#[global_allocator]
+static ALLOCATOR: Locked<KernelAllocator> = Locked::new(KernelAllocator::new());
+
+/// The interface every heap design implements, `Locked` turns any of them
+/// into a `GlobalAlloc`.
+pub trait HeapAllocator {
+    /// Shown in the fragmentation report.
+    const NAME: &'static str;
+
+    /// Hands the allocator the memory it manages.
+    ///
+    /// Unsafe because the caller must guarantee the region is mapped and
+    /// unused, and this must only be called once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Returns a null pointer when the request can't be satisfied.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Unsafe because `ptr` must come from `allocate` with the same `layout`.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    fn heap_size(&self) -> usize;
+
+    /// Describes the memory that is currently free.
+    fn free_space(&self) -> FreeSpace;
+}
+
+/// Where the free memory of a heap is and how broken up it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreeSpace {
+    pub free_bytes: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub allocator: &'static str,
+    pub heap_size: usize,
+    pub bytes_in_use: usize,
+    pub live_allocations: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub free: FreeSpace,
+}
+
+impl HeapStats {
+    /// How much of the free memory can't be handed out as one block, in
+    /// tenths of a percent: 0 when all free memory is one block.
+    pub fn fragmentation_permille(&self) -> usize {
+        if self.free.free_bytes == 0 {
+            return 0;
+        }
+        1000 - self.free.largest_free_block * 1000 / self.free.free_bytes
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fragmentation = self.fragmentation_permille();
+        writeln!(f, "heap allocator:  {}", self.allocator)?;
+        writeln!(f, "  heap size:     {} bytes", self.heap_size)?;
+        writeln!(
+            f,
+            "  in use:        {} bytes in {} allocations ({} allocated, {} freed)",
+            self.bytes_in_use, self.live_allocations, self.allocations, self.deallocations
+        )?;
+        writeln!(
+            f,
+            "  free:          {} bytes in {} blocks, largest {} bytes",
+            self.free.free_bytes, self.free.free_blocks, self.free.largest_free_block
+        )?;
+        write!(f, "  fragmentation: {}.{}%", fragmentation / 10, fragmentation % 10)
+    }
+}
+
+/// A spinlock around a `HeapAllocator`, needed because `GlobalAlloc` only
+/// gets `&self`, which also keeps the counts for the fragmentation report.
+pub struct Locked<A> {
+    inner: Mutex<A>,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    bytes_in_use: AtomicUsize,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: Mutex::new(inner),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            bytes_in_use: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+impl<A: HeapAllocator> Locked<A> {
+    pub fn stats(&self) -> HeapStats {
+        let allocator = self.inner.lock();
+        let allocations = self.allocations.load(Ordering::Relaxed);
+        let deallocations = self.deallocations.load(Ordering::Relaxed);
+        HeapStats {
+            allocator: A::NAME,
+            heap_size: allocator.heap_size(),
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            live_allocations: allocations - deallocations,
+            allocations,
+            deallocations,
+            free: allocator.free_space(),
+        }
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.lock().allocate(layout);
+        if !ptr.is_null() {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            self.bytes_in_use.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.lock().deallocate(ptr, layout);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Rounds `addr` up to `align`, which must be a power of two.
+pub fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Maps the heap region and hands it to the global allocator, after this
+/// `Box`, `Vec` and the rest of `alloc` can be used.
+pub fn init_heap() -> Result<(), PagingError> {
+    let heap_start = Page::containing_address(VirtAddr::new(HEAP_START as u64));
+    let heap_end = Page::containing_address(VirtAddr::new((HEAP_START + HEAP_SIZE) as u64));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    kernel_page_table().map_range(Page::range(heap_start, heap_end), flags)?;
+
+    unsafe {
+        // The region was mapped just above and nothing else points into it.
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    Ok(())
+}
+
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Prints how the kernel heap is used and how fragmented it is over serial.
+pub fn print_fragmentation_report() {
+    serial_println!("{}", stats());
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    // Neither printing path allocates, so both still work with the heap exhausted.
+    println!("ALLOCATION ERROR: {} bytes, aligned to {}", layout.size(), layout.align());
+    serial_println!("ALLOCATION ERROR: {} bytes, aligned to {}", layout.size(), layout.align());
+    panic!("allocation error: {:?}", layout)
+}
+
+/// The checks every heap design has to pass, run against a private heap
+/// carved out of the kernel heap so each design is tested whichever one is
+/// the global allocator.
+#[cfg(test)]
+fn run_heap_suite<A: HeapAllocator>(allocator: A) {
+    use alloc::vec::Vec;
+
+    const TEST_HEAP_SIZE: usize = 64 * 1024;
+    let mut backing: Vec<u8> = Vec::with_capacity(TEST_HEAP_SIZE + 4096);
+    let heap_start = align_up(backing.as_mut_ptr() as usize, 4096);
+    let heap = Locked::new(allocator);
+    unsafe { heap.lock().init(heap_start, TEST_HEAP_SIZE) };
+
+    unsafe {
+        // Values survive next to each other.
+        let layouts = [
+            Layout::from_size_align(1, 1).unwrap(),
+            Layout::from_size_align(24, 8).unwrap(),
+            Layout::from_size_align(300, 16).unwrap(),
+            Layout::from_size_align(4096, 4096).unwrap(),
+        ];
+        let mut ptrs = [core::ptr::null_mut(); 4];
+        for (i, layout) in layouts.iter().enumerate() {
+            ptrs[i] = heap.alloc(*layout);
+            assert!(!ptrs[i].is_null());
+            assert_eq!(ptrs[i] as usize % layout.align(), 0);
+            ptrs[i].write_bytes(i as u8 + 1, layout.size());
+        }
+        for (i, layout) in layouts.iter().enumerate() {
+            assert!((0..layout.size()).all(|offset| *ptrs[i].add(offset) == i as u8 + 1));
+            heap.dealloc(ptrs[i], *layout);
+        }
+
+        // Allocating far more than the heap in total needs freed memory to be reused.
+        let small = Layout::from_size_align(64, 8).unwrap();
+        for _ in 0..(4 * TEST_HEAP_SIZE / 64) {
+            let ptr = heap.alloc(small);
+            assert!(!ptr.is_null());
+            heap.dealloc(ptr, small);
+        }
+
+        // Running out hands back null rather than failing, and freeing
+        // everything makes the memory usable again.
+        let block = Layout::from_size_align(1024, 8).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = heap.alloc(block);
+            if ptr.is_null() {
+                break;
+            }
+            blocks.push(ptr);
+        }
+        assert!(blocks.len() >= TEST_HEAP_SIZE / 1024 / 2);
+        for ptr in blocks.drain(..) {
+            heap.dealloc(ptr, block);
+        }
+        let ptr = heap.alloc(block);
+        assert!(!ptr.is_null());
+        heap.dealloc(ptr, block);
+    }
+
+    let stats = heap.stats();
+    assert_eq!(stats.live_allocations, 0);
+    assert_eq!(stats.bytes_in_use, 0);
+    serial_println!("");
+    serial_println!("{}", stats);
+}
@@ -0,0 +1,96 @@
+use super::{align_up, FreeSpace, HeapAllocator};
+use alloc::alloc::Layout;
+
+/// Hands out memory by moving a pointer forward and never reuses single
+/// allocations, the whole heap only becomes free again once every
+/// allocation has been freed.
+///
+/// The fastest design there is, good for memory that is allocated while
+/// booting and kept for good, a poor fit for anything that frees and
+/// allocates over and over.
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    const NAME: &'static str = "bump";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return core::ptr::null_mut(),
+        };
+
+        if alloc_end > self.heap_end {
+            core::ptr::null_mut() // out of memory
+        } else {
+            self.next = alloc_end;
+            self.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        // Only what lies past `next` can be handed out, freed allocations
+        // below it are lost until the heap empties.
+        let free_bytes = self.heap_end - self.next;
+        FreeSpace {
+            free_bytes,
+            free_blocks: if free_bytes > 0 { 1 } else { 0 },
+            largest_free_block: free_bytes,
+        }
+    }
+}
+
+#[test_case]
+fn test_bump_heap_suite() {
+    super::run_heap_suite(BumpAllocator::new());
+}
+
+#[test_case]
+fn test_bump_resets_when_empty() {
+    let mut backing = alloc::vec![0u64; 512];
+    let mut heap = BumpAllocator::new();
+    unsafe { heap.init(backing.as_mut_ptr() as usize, 4096) };
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let first = heap.allocate(layout);
+    let second = heap.allocate(layout);
+    assert!(second > first);
+    unsafe {
+        heap.deallocate(first, layout);
+        heap.deallocate(second, layout);
+    }
+    assert_eq!(heap.allocate(layout), first);
+}
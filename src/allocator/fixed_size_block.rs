@@ -0,0 +1,124 @@
+use super::linked_list::LinkedListAllocator;
+use super::{FreeSpace, HeapAllocator};
+use alloc::alloc::Layout;
+use core::mem;
+
+/// The sizes blocks come in. A block is aligned to its own size, so every
+/// size has to be a power of two, like alignments are.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Rounds each allocation up to one of `BLOCK_SIZES` and keeps a list of
+/// free blocks per size, so allocating and freeing is a push or pop on a
+/// list. Larger allocations, and blocks the lists run out of, come from a
+/// linked list allocator underneath.
+///
+/// Freed blocks stay in their size's list for good, so memory that was once
+/// used for small blocks is never available for big ones again.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        FixedSizeBlockAllocator {
+            list_heads: [None, None, None, None, None, None, None, None, None],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+}
+
+// Which of `BLOCK_SIZES` fits `layout`, both its size and its alignment, or
+// None when it's too big for all of them.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    const NAME: &'static str = "fixed-size-block";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // The list is empty, so carve a fresh block out of the
+                    // heap underneath. Sizes are powers of two, so a block
+                    // can be aligned to its size.
+                    let block_size = BLOCK_SIZES[index];
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    self.fallback_allocator.allocate(layout)
+                }
+            },
+            None => self.fallback_allocator.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // The freed block holds the list node itself, so it has to be
+                // big enough and aligned enough for one.
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => self.fallback_allocator.deallocate(ptr, layout),
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.fallback_allocator.heap_size()
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        let mut free = self.fallback_allocator.free_space();
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            let mut current = head.as_ref().map(|node| &**node);
+            while let Some(node) = current {
+                free.free_bytes += block_size;
+                free.free_blocks += 1;
+                free.largest_free_block = free.largest_free_block.max(block_size);
+                current = node.next.as_ref().map(|next| &**next);
+            }
+        }
+        free
+    }
+}
+
+#[test_case]
+fn test_fixed_size_block_heap_suite() {
+    super::run_heap_suite(FixedSizeBlockAllocator::new());
+}
+
+#[test_case]
+fn test_fixed_size_block_reuses_freed_block() {
+    let mut backing = alloc::vec![0u64; 1024];
+    let mut heap = FixedSizeBlockAllocator::new();
+    unsafe { heap.init(backing.as_mut_ptr() as usize, 8192) };
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let first = heap.allocate(layout);
+    assert!(!first.is_null());
+    unsafe { heap.deallocate(first, layout) };
+    // 40 bytes rounds up to the 64 byte class, any layout in that class gets the block back.
+    assert_eq!(heap.allocate(Layout::from_size_align(64, 64).unwrap()), first);
+    assert!(heap.allocate(Layout::from_size_align(16 * 1024, 8).unwrap()).is_null());
+}
@@ -0,0 +1,211 @@
+use super::{align_up, FreeSpace, HeapAllocator};
+use alloc::alloc::Layout;
+use core::{mem, ptr};
+
+/// A free region, stored at the start of the region itself.
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode {
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// Keeps the free regions of the heap in a list sorted by address and
+/// allocates from the first one that fits.
+///
+/// Because the list is sorted, a freed region that touches its neighbours
+/// is merged with them, so the heap doesn't crumble into pieces too small
+/// to use after a while of allocating and freeing.
+pub struct LinkedListAllocator {
+    head: *mut ListNode,
+    heap_size: usize,
+}
+
+// The raw pointers only ever point into the heap, and the allocator is only
+// reached through the lock in `Locked`.
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ptr::null_mut(),
+            heap_size: 0,
+        }
+    }
+
+    /// Puts a region back into the list, merging it with the regions right
+    /// before and after it when they touch.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        let mut previous: *mut ListNode = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() && (*current).start_addr() < addr {
+            previous = current;
+            current = (*current).next;
+        }
+        assert!(
+            previous.is_null() || (*previous).end_addr() <= addr,
+            "freed region {:#x} overlaps a free region",
+            addr
+        );
+        assert!(
+            current.is_null() || addr + size <= (*current).start_addr(),
+            "freed region {:#x} overlaps a free region",
+            addr
+        );
+
+        let node = addr as *mut ListNode;
+        node.write(ListNode { size, next: current });
+        if previous.is_null() {
+            self.head = node;
+        } else {
+            (*previous).next = node;
+        }
+
+        if !current.is_null() && (*node).end_addr() == (*current).start_addr() {
+            (*node).size += (*current).size;
+            (*node).next = (*current).next;
+        }
+        if !previous.is_null() && (*previous).end_addr() == (*node).start_addr() {
+            (*previous).size += (*node).size;
+            (*previous).next = (*node).next;
+        }
+    }
+
+    /// Checks whether an allocation fits in `region`, returning where it
+    /// would start. Any space left over in front or behind has to be big
+    /// enough to become a region of its own.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Option<usize> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region.end_addr() {
+            return None;
+        }
+
+        let front = alloc_start - region.start_addr();
+        let back = region.end_addr() - alloc_end;
+        if (front > 0 && front < mem::size_of::<ListNode>())
+            || (back > 0 && back < mem::size_of::<ListNode>())
+        {
+            return None;
+        }
+        Some(alloc_start)
+    }
+
+    /// Grows the layout so the memory can hold a `ListNode` once it is freed.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    const NAME: &'static str = "linked-list";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_size = heap_size;
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        unsafe {
+            let mut previous: *mut ListNode = ptr::null_mut();
+            let mut current = self.head;
+            while !current.is_null() {
+                if let Some(alloc_start) = LinkedListAllocator::alloc_from_region(&*current, size, align) {
+                    let region_start = (*current).start_addr();
+                    let region_end = (*current).end_addr();
+                    if previous.is_null() {
+                        self.head = (*current).next;
+                    } else {
+                        (*previous).next = (*current).next;
+                    }
+
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start);
+                    }
+                    if alloc_start + size < region_end {
+                        self.add_free_region(alloc_start + size, region_end - alloc_start - size);
+                    }
+                    return alloc_start as *mut u8;
+                }
+                previous = current;
+                current = (*current).next;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
+
+    fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        let mut free = FreeSpace::default();
+        let mut current = self.head;
+        while !current.is_null() {
+            let size = unsafe { (*current).size };
+            free.free_bytes += size;
+            free.free_blocks += 1;
+            free.largest_free_block = free.largest_free_block.max(size);
+            current = unsafe { (*current).next };
+        }
+        free
+    }
+}
+
+#[test_case]
+fn test_linked_list_heap_suite() {
+    super::run_heap_suite(LinkedListAllocator::new());
+}
+
+#[test_case]
+fn test_linked_list_coalesces_free_regions() {
+    let mut backing = alloc::vec![0u64; 512];
+    let mut heap = LinkedListAllocator::new();
+    unsafe { heap.init(backing.as_mut_ptr() as usize, 4096) };
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    let a = heap.allocate(layout);
+    let b = heap.allocate(layout);
+    let c = heap.allocate(layout);
+    assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+    // Freeing the middle one first leaves a hole, freeing its neighbours
+    // has to merge everything back into a single region.
+    unsafe {
+        heap.deallocate(b, layout);
+        assert_eq!(heap.free_space().free_blocks, 2);
+        heap.deallocate(a, layout);
+        heap.deallocate(c, layout);
+    }
+    assert_eq!(
+        heap.free_space(),
+        FreeSpace {
+            free_bytes: 4096,
+            free_blocks: 1,
+            largest_free_block: 4096
+        }
+    );
+    assert!(!heap.allocate(Layout::from_size_align(4096, 8).unwrap()).is_null());
+}
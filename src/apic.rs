@@ -0,0 +1,306 @@
+use crate::acpi::{self, Madt};
+use crate::memory::paging::{kernel_page_table, PagingError};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+// The local APIC of each CPU takes interrupts and has a timer of its own, the
+// I/O APICs route device interrupts to the local APICs. Both are programmed
+// through memory mapped registers.
+
+/// The local APIC timer fires on this vector, right after the 16 IRQ vectors.
+pub const LAPIC_TIMER_VECTOR: u8 = 48;
+/// Where the local APIC sends interrupts it dropped, must end in 0xf on old CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: Once<Mutex<Vec<IoApic>>> = Once::new();
+static MADT: Once<Madt> = Once::new();
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Why we couldn't switch to the APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// CPUID says there is no local APIC.
+    NotSupported,
+    /// No ACPI, or no MADT in it, so we don't know where the I/O APICs are.
+    NoMadt,
+    NoIoApic,
+    Paging(PagingError),
+}
+
+impl From<PagingError> for ApicError {
+    fn from(error: PagingError) -> ApicError {
+        ApicError::Paging(error)
+    }
+}
+
+// Local APIC register offsets.
+const LAPIC_ID: u32 = 0x20;
+const LAPIC_TASK_PRIORITY: u32 = 0x80;
+const LAPIC_EOI: u32 = 0xb0;
+const LAPIC_SPURIOUS: u32 = 0xf0;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u32 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+/// The local APIC of the CPU we're running on.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, register: u32) -> u32 {
+        // `base` is the APIC's register page, mapped uncached in `init`.
+        unsafe { ((self.base + u64::from(register)).as_ptr::<u32>()).read_volatile() }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe { ((self.base + u64::from(register)).as_mut_ptr::<u32>()).write_volatile(value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    fn enable(&self) {
+        self.write(LAPIC_TASK_PRIORITY, 0); // accept every priority
+        self.write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+        self.stop_timer();
+    }
+
+    /// Starts the timer counting down from `initial_count`, at the bus
+    /// clock divided by 16, firing `LAPIC_TIMER_VECTOR` when it reaches zero.
+    pub fn start_timer(&self, initial_count: u32, mode: TimerMode) {
+        let mode = match mode {
+            TimerMode::OneShot => 0,
+            TimerMode::Periodic => LVT_TIMER_PERIODIC,
+        };
+        self.write(LAPIC_TIMER_DIVIDE, 0b0011); // divide by 16
+        self.write(LAPIC_LVT_TIMER, mode | u32::from(LAPIC_TIMER_VECTOR));
+        self.write(LAPIC_TIMER_INITIAL_COUNT, initial_count);
+    }
+
+    pub fn stop_timer(&self) {
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED | u32::from(LAPIC_TIMER_VECTOR));
+        self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(LAPIC_TIMER_CURRENT_COUNT)
+    }
+}
+
+// I/O APIC registers are reached indirectly: write the register number to
+// IOREGSEL, then access it through IOWIN.
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        // `base` is the I/O APIC's register page, mapped uncached in `init`.
+        unsafe {
+            (self.base + IOAPIC_REGSEL).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IOAPIC_WINDOW).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            (self.base + IOAPIC_REGSEL).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IOAPIC_WINDOW).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    /// Sends global system interrupt `gsi` to `vector` on the local APIC
+    /// with ID `destination`, starting out masked.
+    fn route(&mut self, gsi: u32, vector: u8, destination: u8, active_low: bool, level_triggered: bool) {
+        let mut low = u32::from(vector) | REDIRECTION_MASKED;
+        if active_low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level_triggered {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, low);
+    }
+
+    fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        let low = self.read(register);
+        self.write(register, if masked { low | REDIRECTION_MASKED } else { low & !REDIRECTION_MASKED });
+    }
+}
+
+/// Whether CPUID reports a local APIC.
+pub fn is_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+    const CPUID_APIC: u32 = 1 << 9;
+    unsafe { __cpuid(1) }.edx & CPUID_APIC != 0
+}
+
+/// Finds the APICs through CPUID and the MADT, enables the local APIC and
+/// routes the 16 ISA IRQs to `irq_vector_base + irq`, all masked. IRQs
+/// whose GSI another IRQ was moved to are left out, see `Madt::isa_route`.
+///
+/// Masking the 8259 PICs is left to the caller, they stay untouched if this fails.
+pub fn init(irq_vector_base: u8) -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let local_apic = LocalApic { base: map_registers(madt.local_apic_address)? };
+    let mut io_apics = Vec::new();
+    for entry in madt.io_apics.iter() {
+        let mut io_apic = IoApic {
+            base: map_registers(entry.address)?,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        // Bits 16..24 of the version register hold the last entry's index.
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apics.push(io_apic);
+    }
+
+    local_apic.enable();
+    for irq in 0..16 {
+        let route = match madt.isa_route(irq) {
+            Some(route) => route,
+            None => continue,
+        };
+        if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(route.gsi)) {
+            io_apic.route(route.gsi, irq_vector_base + irq, local_apic.id(), route.active_low, route.level_triggered);
+        }
+    }
+
+    LOCAL_APIC.call_once(|| local_apic);
+    IO_APICS.call_once(|| Mutex::new(io_apics));
+    MADT.call_once(|| madt);
+    Ok(())
+}
+
+fn map_registers(phys: PhysAddr) -> Result<VirtAddr, ApicError> {
+    // Both kinds of APIC have all their registers within one page.
+    Ok(unsafe { kernel_page_table().map_mmio(phys, 0x1000) }?)
+}
+
+/// The local APIC, once `init` has succeeded.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.wait()
+}
+
+pub fn is_enabled() -> bool {
+    local_apic().is_some()
+}
+
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = local_apic() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+/// Masks or unmasks ISA IRQ `irq` at whichever I/O APIC it's routed to.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let (madt, io_apics) = match (MADT.wait(), IO_APICS.wait()) {
+        (Some(madt), Some(io_apics)) => (madt, io_apics),
+        _ => return,
+    };
+    // An IRQ that isn't wired anywhere mustn't touch the entry of the one
+    // that took its GSI.
+    let gsi = match madt.isa_route(irq) {
+        Some(route) => route.gsi,
+        None => return,
+    };
+    if let Some(io_apic) = io_apics.lock().iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        io_apic.set_masked(gsi, masked);
+    }
+}
+
+/// How often the local APIC timer has fired.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+/// Registers the local APIC's own interrupt handlers.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt[usize::from(LAPIC_TIMER_VECTOR)].set_handler_fn(timer_handler);
+    idt[usize::from(SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
+}
+
+extern "x86-interrupt" fn timer_handler(_stack_frame: &mut InterruptStackFrame) {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    // Nothing was actually delivered, so there's nothing to acknowledge either.
+}
+
+#[test_case]
+fn test_local_apic_timer_fires() {
+    // Only runs when QEMU gave us an APIC, the PIC fallback has no such timer.
+    if let Some(local_apic) = local_apic() {
+        let ticks = timer_ticks();
+        local_apic.start_timer(10_000, TimerMode::Periodic);
+        let mut spins = 0u64;
+        while timer_ticks() < ticks + 3 && spins < 100_000_000 {
+            spins += 1;
+        }
+        local_apic.stop_timer();
+        assert!(timer_ticks() >= ticks + 3, "local APIC timer never fired");
+    }
+}
+
+#[test_case]
+fn test_clock_ticks_through_the_io_apic() {
+    // The clock's IRQ 0 has to survive IRQ 2 being routed and masked, which
+    // shares its GSI on QEMU.
+    if is_enabled() {
+        set_irq_masked(2, true);
+        let ticks = crate::time::ticks();
+        let mut spins = 0u64;
+        while crate::time::ticks() < ticks + 3 && spins < 100_000_000 {
+            spins += 1;
+        }
+        assert!(crate::time::ticks() >= ticks + 3, "no clock ticks while the APIC is in use");
+    }
+}
@@ -0,0 +1,55 @@
+// Kernel command-line options, like `clocksource=hpet keymap=uk`.
+//
+// The bootloader has no way to hand us a command line, so it's baked in at
+// build time from the `KUROGANE_CMDLINE` environment variable:
+//
+//     KUROGANE_CMDLINE="clocksource=hpet" cargo run
+//
+// Test kernels can give their own with `set`.
+
+// Set by `set`, in place of the one from the build.
+static OVERRIDE: spin::Once<&'static str> = spin::Once::new();
+
+/// The whole command line, empty when none was given.
+pub fn raw() -> &'static str {
+    match OVERRIDE.wait() {
+        Some(cmdline) => *cmdline,
+        None => option_env!("KUROGANE_CMDLINE").unwrap_or(""),
+    }
+}
+
+/// Replaces the command line from the build, for test kernels that need
+/// options of their own. Only the first call counts, and it has to come
+/// before `kurogane_os::init`, which is where the options are read.
+pub fn set(cmdline: &'static str) {
+    OVERRIDE.call_once(|| cmdline);
+}
+
+/// The value of option `key`, for an option written as `key=value`. An option
+/// given more than once takes its last value.
+pub fn get(key: &str) -> Option<&'static str> {
+    find(raw(), key)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|option| {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name == key => Some(value),
+                _ => None,
+            }
+        })
+        .last()
+}
+
+#[test_case]
+fn test_find_options() {
+    let cmdline = "clocksource=hpet  keymap=uk quiet keymap=dvorak";
+    assert_eq!(find(cmdline, "clocksource"), Some("hpet"));
+    assert_eq!(find(cmdline, "keymap"), Some("dvorak"));
+    assert_eq!(find(cmdline, "quiet"), None);
+    assert_eq!(find(cmdline, "clock"), None);
+    assert_eq!(find("", "clocksource"), None);
+}
@@ -0,0 +1,438 @@
+use crate::keyboard::KeyStream;
+use crate::serial::SerialStream;
+use crate::vga_buffer::{Console, BUFFER_WIDTH};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Reading a line of input with some editing: the cursor keys, Home and End,
+// Insert to switch between inserting and overwriting, Ctrl-U and Ctrl-W to
+// delete, and Up and Down to go through earlier lines.
+//
+// The editor itself only sees `EditKey`s, so the same one works for the
+// keyboard and the screen as well as for a terminal on the serial port.
+
+/// How many lines `read_line` remembers.
+pub const HISTORY_SIZE: usize = 32;
+
+// We can't know where on its line a serial terminal is, so keep lines short
+// enough not to wrap after a prompt.
+const SERIAL_LINE_LENGTH: usize = 64;
+
+static HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// What the editor does, whatever key it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKey {
+    Char(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Switches between inserting and overwriting.
+    Insert,
+    Up,
+    Down,
+    Enter,
+    /// Ctrl-U: deletes everything before the cursor.
+    KillLine,
+    /// Ctrl-W: deletes the word before the cursor.
+    KillWord,
+}
+
+impl EditKey {
+    /// The edit a key press from the keyboard stands for. Ctrl-U and Ctrl-W
+    /// only come through with the keyboard mapping Ctrl combinations, which
+    /// it does unless `keyboard::set_layout` said otherwise.
+    pub fn from_key(key: DecodedKey) -> Option<EditKey> {
+        match key {
+            DecodedKey::Unicode('\n') => Some(EditKey::Enter),
+            DecodedKey::Unicode('\u{8}') => Some(EditKey::Backspace),
+            DecodedKey::Unicode('\u{7f}') => Some(EditKey::Delete),
+            DecodedKey::Unicode('\u{15}') => Some(EditKey::KillLine),
+            DecodedKey::Unicode('\u{17}') => Some(EditKey::KillWord),
+            DecodedKey::Unicode(character) => Some(EditKey::Char(character)),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(EditKey::Left),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => Some(EditKey::Right),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Some(EditKey::Up),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Some(EditKey::Down),
+            DecodedKey::RawKey(KeyCode::Home) => Some(EditKey::Home),
+            DecodedKey::RawKey(KeyCode::End) => Some(EditKey::End),
+            DecodedKey::RawKey(KeyCode::Insert) => Some(EditKey::Insert),
+            DecodedKey::RawKey(_) => None,
+        }
+    }
+}
+
+/// Turns the bytes a terminal sends into `EditKey`s, including the VT100
+/// escape sequences for the cursor and editing keys.
+#[derive(Debug, Default)]
+pub struct TerminalInput {
+    state: EscapeState,
+    after_carriage_return: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Normal,
+    Escape, // got ESC
+    Sequence, // got ESC [ or ESC O
+    Tilde(u8), // got ESC [ and a number, waiting for `~`
+}
+
+impl Default for EscapeState {
+    fn default() -> EscapeState {
+        EscapeState::Normal
+    }
+}
+
+impl TerminalInput {
+    pub fn new() -> TerminalInput {
+        TerminalInput::default()
+    }
+
+    pub fn add_byte(&mut self, byte: u8) -> Option<EditKey> {
+        let after_carriage_return = core::mem::replace(&mut self.after_carriage_return, byte == b'\r');
+        let (state, key) = match (self.state, byte) {
+            (EscapeState::Normal, 0x1b) => (EscapeState::Escape, None),
+            // Some terminals send CR LF for Enter.
+            (EscapeState::Normal, b'\n') if after_carriage_return => (EscapeState::Normal, None),
+            (EscapeState::Normal, byte) => (EscapeState::Normal, control_key(byte)),
+            (EscapeState::Escape, b'[') | (EscapeState::Escape, b'O') => (EscapeState::Sequence, None),
+            (EscapeState::Escape, _) => (EscapeState::Normal, None),
+            (EscapeState::Sequence, byte) => match byte {
+                b'A' => (EscapeState::Normal, Some(EditKey::Up)),
+                b'B' => (EscapeState::Normal, Some(EditKey::Down)),
+                b'C' => (EscapeState::Normal, Some(EditKey::Right)),
+                b'D' => (EscapeState::Normal, Some(EditKey::Left)),
+                b'H' => (EscapeState::Normal, Some(EditKey::Home)),
+                b'F' => (EscapeState::Normal, Some(EditKey::End)),
+                b'0'..=b'9' => (EscapeState::Tilde(byte - b'0'), None),
+                _ => (EscapeState::Normal, None),
+            },
+            (EscapeState::Tilde(number), b'0'..=b'9') => {
+                let number = number.saturating_mul(10).saturating_add(byte - b'0');
+                (EscapeState::Tilde(number), None)
+            }
+            (EscapeState::Tilde(number), b'~') => (
+                EscapeState::Normal,
+                match number {
+                    1 | 7 => Some(EditKey::Home),
+                    2 => Some(EditKey::Insert),
+                    3 => Some(EditKey::Delete),
+                    4 | 8 => Some(EditKey::End),
+                    _ => None,
+                },
+            ),
+            (EscapeState::Tilde(_), _) => (EscapeState::Normal, None),
+        };
+        self.state = state;
+        key
+    }
+}
+
+fn control_key(byte: u8) -> Option<EditKey> {
+    match byte {
+        b'\r' | b'\n' => Some(EditKey::Enter),
+        0x08 | 0x7f => Some(EditKey::Backspace), // terminals send DEL for Backspace
+        0x15 => Some(EditKey::KillLine),
+        0x17 => Some(EditKey::KillWord),
+        0x20..=0x7e => Some(EditKey::Char(byte as char)),
+        _ => None,
+    }
+}
+
+/// The line being edited, and where the cursor is in it.
+struct LineEditor {
+    line: Vec<u8>,
+    cursor: usize,
+    overwrite: bool,
+    max_length: usize,
+    // Which history entry is shown, and what was typed before going there.
+    history_index: Option<usize>,
+    draft: Vec<u8>,
+}
+
+impl LineEditor {
+    fn new(max_length: usize) -> LineEditor {
+        LineEditor {
+            line: Vec::new(),
+            cursor: 0,
+            overwrite: false,
+            max_length,
+            history_index: None,
+            draft: Vec::new(),
+        }
+    }
+
+    fn text(&self) -> &str {
+        // Only printable ASCII ever gets in, see `edit`.
+        core::str::from_utf8(&self.line).unwrap()
+    }
+
+    /// Applies `key`, returning whether the line is finished.
+    fn edit(&mut self, key: EditKey, history: &[String]) -> bool {
+        match key {
+            EditKey::Enter => return true,
+            // The screen can only show ASCII for now.
+            EditKey::Char(character) if character.is_ascii() && !character.is_ascii_control() => {
+                let byte = character as u8;
+                if self.overwrite && self.cursor < self.line.len() {
+                    self.line[self.cursor] = byte;
+                    self.cursor += 1;
+                } else if self.line.len() < self.max_length {
+                    self.line.insert(self.cursor, byte);
+                    self.cursor += 1;
+                }
+            }
+            EditKey::Char(_) => {}
+            EditKey::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            EditKey::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            EditKey::Backspace | EditKey::Delete => {}
+            EditKey::Left => self.cursor = self.cursor.saturating_sub(1),
+            EditKey::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            EditKey::Home => self.cursor = 0,
+            EditKey::End => self.cursor = self.line.len(),
+            EditKey::Insert => self.overwrite = !self.overwrite,
+            EditKey::KillLine => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            EditKey::KillWord => {
+                // Spaces right before the cursor go along with the word.
+                let before = &self.line[..self.cursor];
+                let word_end = before.iter().rposition(|&byte| byte != b' ').map_or(0, |index| index + 1);
+                let word_start = before[..word_end].iter().rposition(|&byte| byte == b' ').map_or(0, |index| index + 1);
+                self.line.drain(word_start..self.cursor);
+                self.cursor = word_start;
+            }
+            EditKey::Up => {
+                let index = match self.history_index {
+                    None if history.is_empty() => return false,
+                    None => {
+                        self.draft = core::mem::replace(&mut self.line, Vec::new());
+                        history.len() - 1
+                    }
+                    Some(index) => index.saturating_sub(1),
+                };
+                self.show_history(history, Some(index));
+            }
+            EditKey::Down => match self.history_index {
+                None => {}
+                Some(index) if index + 1 < history.len() => self.show_history(history, Some(index + 1)),
+                Some(_) => self.show_history(history, None),
+            },
+        }
+        false
+    }
+
+    fn show_history(&mut self, history: &[String], index: Option<usize>) {
+        self.line = match index {
+            Some(index) => history[index].bytes().take(self.max_length).collect(),
+            None => core::mem::replace(&mut self.draft, Vec::new()),
+        };
+        self.history_index = index;
+        self.cursor = self.line.len();
+    }
+}
+
+/// Where the line being edited is shown.
+trait LineView {
+    /// Shows `line`, with the cursor before its `cursor`th character.
+    fn show(&mut self, line: &str, cursor: usize);
+    fn finish(&mut self);
+}
+
+/// The cursor's row on a virtual console, from wherever the output left off.
+struct ScreenView {
+    console: Console,
+    start_column: usize,
+}
+
+impl LineView for ScreenView {
+    fn show(&mut self, line: &str, cursor: usize) {
+        interrupts::without_interrupts(|| {
+            let mut writer = self.console.writer().lock();
+            writer.set_column_position(self.start_column);
+            writer.write_str(line).unwrap();
+            writer.clear_to_end_of_row();
+            writer.set_column_position(self.start_column + cursor);
+        });
+    }
+
+    fn finish(&mut self) {
+        writeln!(self.console).unwrap();
+    }
+}
+
+/// A VT100 terminal on the serial port, moved around with escape sequences.
+struct SerialView {
+    cursor: usize,
+}
+
+impl LineView for SerialView {
+    fn show(&mut self, line: &str, cursor: usize) {
+        if self.cursor > 0 {
+            crate::serial_print!("\x1b[{}D", self.cursor);
+        }
+        crate::serial_print!("{}\x1b[K", line);
+        if line.len() > cursor {
+            crate::serial_print!("\x1b[{}D", line.len() - cursor);
+        }
+        self.cursor = cursor;
+    }
+
+    fn finish(&mut self) {
+        crate::serial_println!();
+    }
+}
+
+async fn edit_line(mut keys: impl Stream<Item = EditKey> + Unpin, view: &mut impl LineView, max_length: usize) -> String {
+    let mut editor = LineEditor::new(max_length);
+    while let Some(key) = keys.next().await {
+        // Nobody else touches the history while we wait for keys, so it's
+        // only locked for a moment.
+        let done = {
+            let history = HISTORY.lock();
+            editor.edit(key, &history)
+        };
+        if done {
+            break;
+        }
+        view.show(editor.text(), editor.cursor);
+    }
+    view.finish();
+    let line = String::from(editor.text());
+    add_to_history(&line);
+    line
+}
+
+fn add_to_history(line: &str) {
+    let mut history = HISTORY.lock();
+    if line.is_empty() || history.last().map(String::as_str) == Some(line) {
+        return;
+    }
+    if history.len() == HISTORY_SIZE {
+        history.remove(0);
+    }
+    history.push(String::from(line));
+}
+
+/// Reads a line typed on the keyboard, showing it on the screen where the
+/// output left off. The line can't be longer than the rest of the row.
+pub async fn read_line() -> String {
+    read_line_on(Console::log()).await
+}
+
+/// Like `read_line`, showing the line on `console` instead of where
+/// `print!` goes. Keys are read whichever console is on the screen.
+pub async fn read_line_on(console: Console) -> String {
+    let start_column = interrupts::without_interrupts(|| console.writer().lock().column_position());
+    let keys = KeyStream::new().filter_map(|key| async move { EditKey::from_key(key) });
+    let max_length = (BUFFER_WIDTH - 1).saturating_sub(start_column);
+    edit_line(Box::pin(keys), &mut ScreenView { console, start_column }, max_length).await
+}
+
+/// Reads a line from a terminal on the serial port, editing it there.
+pub async fn read_serial_line() -> String {
+    let mut input = TerminalInput::new();
+    let keys = SerialStream::new().filter_map(move |byte| {
+        let key = input.add_byte(byte);
+        async move { key }
+    });
+    edit_line(Box::pin(keys), &mut SerialView { cursor: 0 }, SERIAL_LINE_LENGTH).await
+}
+
+#[cfg(test)]
+fn type_keys(keys: &[EditKey], history: &[String]) -> (String, usize) {
+    let mut editor = LineEditor::new(20);
+    for &key in keys {
+        editor.edit(key, history);
+    }
+    (String::from(editor.text()), editor.cursor)
+}
+
+#[cfg(test)]
+fn chars(text: &str) -> Vec<EditKey> {
+    text.chars().map(EditKey::Char).collect()
+}
+
+#[test_case]
+fn test_insert_and_overwrite() {
+    let mut keys = chars("helo");
+    keys.extend_from_slice(&[EditKey::Left, EditKey::Char('l'), EditKey::Home, EditKey::Insert, EditKey::Char('j')]);
+    assert_eq!(type_keys(&keys, &[]), (String::from("jello"), 1));
+}
+
+#[test_case]
+fn test_deleting() {
+    let mut keys = chars("abcd");
+    keys.extend_from_slice(&[EditKey::Backspace, EditKey::Home, EditKey::Delete, EditKey::End, EditKey::Right]);
+    assert_eq!(type_keys(&keys, &[]), (String::from("bc"), 2));
+}
+
+#[test_case]
+fn test_kill_word_and_line() {
+    let mut keys = chars("ls -l  /boot  ");
+    keys.push(EditKey::KillWord);
+    assert_eq!(type_keys(&keys, &[]), (String::from("ls -l  "), 7));
+    keys.extend_from_slice(&[EditKey::Left, EditKey::Left, EditKey::KillLine]);
+    assert_eq!(type_keys(&keys, &[]), (String::from("  "), 0));
+}
+
+#[test_case]
+fn test_line_length_is_limited() {
+    let keys = chars("0123456789012345678901234");
+    assert_eq!(type_keys(&keys, &[]).0.len(), 20);
+}
+
+#[test_case]
+fn test_history() {
+    let history = [String::from("first"), String::from("second")];
+    let mut keys = chars("draft");
+    keys.extend_from_slice(&[EditKey::Up, EditKey::Up, EditKey::Up]);
+    assert_eq!(type_keys(&keys, &history), (String::from("first"), 5));
+    keys.push(EditKey::Down);
+    assert_eq!(type_keys(&keys, &history).0, "second");
+    keys.push(EditKey::Down);
+    assert_eq!(type_keys(&keys, &history).0, "draft");
+}
+
+#[test_case]
+fn test_terminal_input() {
+    let mut input = TerminalInput::new();
+    let bytes = b"a\x1b[D\x1b[3~\x1bOH\x1b[4~\x7f\x15\r\n";
+    let keys: Vec<EditKey> = bytes.iter().filter_map(|&byte| input.add_byte(byte)).collect();
+    assert_eq!(
+        keys,
+        [
+            EditKey::Char('a'),
+            EditKey::Left,
+            EditKey::Delete,
+            EditKey::Home,
+            EditKey::End,
+            EditKey::Backspace,
+            EditKey::KillLine,
+            EditKey::Enter,
+        ]
+    );
+}
+
+#[test_case]
+fn test_keyboard_keys() {
+    assert_eq!(EditKey::from_key(DecodedKey::Unicode('\u{17}')), Some(EditKey::KillWord));
+    assert_eq!(EditKey::from_key(DecodedKey::RawKey(KeyCode::ArrowUp)), Some(EditKey::Up));
+    assert_eq!(EditKey::from_key(DecodedKey::RawKey(KeyCode::F1)), None);
+}
@@ -0,0 +1,461 @@
+use crate::gdt;
+use crate::vga_buffer::Console;
+use crate::{println, serial_println};
+use core::fmt;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+// Handlers for the CPU exceptions we can't recover from. Each one collects
+// what it can about the fault into an `ExceptionReport`, prints it on the
+// screen and over serial, and panics. Breakpoints and double faults are
+// handled in `interrupts.rs`.
+
+/// The exceptions this module handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    InvalidOpcode,
+    X87FloatingPoint,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtectionFault,
+    PageFault,
+    AlignmentCheck,
+    MachineCheck,
+    SimdFloatingPoint,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        match self {
+            Exception::DivideError => 0,
+            Exception::InvalidOpcode => 6,
+            Exception::SegmentNotPresent => 11,
+            Exception::StackSegmentFault => 12,
+            Exception::GeneralProtectionFault => 13,
+            Exception::PageFault => 14,
+            Exception::X87FloatingPoint => 16,
+            Exception::AlignmentCheck => 17,
+            Exception::MachineCheck => 18,
+            Exception::SimdFloatingPoint => 19,
+        }
+    }
+
+    /// The short name Intel's manuals use, like `#GP`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::InvalidOpcode => "#UD",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK-SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING-POINT ERROR",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING-POINT EXCEPTION",
+        }
+    }
+}
+
+/// Which descriptor table a selector error code points into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code pushed by #NP, #SS and #GP (and #TS), which names the
+/// segment selector or IDT vector involved, or is zero when the fault had
+/// nothing to do with one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    pub fn is_null(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Set when the fault happened while delivering an external interrupt.
+    pub fn is_external(self) -> bool {
+        self.0 & 0b001 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        if self.0 & 0b010 != 0 {
+            DescriptorTable::Idt
+        } else if self.0 & 0b100 != 0 {
+            DescriptorTable::Ldt
+        } else {
+            DescriptorTable::Gdt
+        }
+    }
+
+    /// The descriptor index, for the IDT this is the interrupt vector.
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_null() {
+            return write!(f, "none");
+        }
+        match self.table() {
+            DescriptorTable::Idt => write!(f, "IDT vector {}", self.index())?,
+            DescriptorTable::Gdt => write!(f, "GDT index {} (selector {:#x})", self.index(), self.0 & !0b11)?,
+            DescriptorTable::Ldt => write!(f, "LDT index {} (selector {:#x})", self.index(), self.0 & !0b11)?,
+        }
+        if self.is_external() {
+            write!(f, ", during an external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// What an exception tells us beyond where it happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionDetail {
+    None,
+    Selector(SelectorErrorCode),
+    PageFault {
+        /// CR2, the virtual address whose access faulted.
+        accessed_address: VirtAddr,
+        error_code: PageFaultErrorCode,
+    },
+    Simd { mxcsr: u32 },
+    X87 { status_word: u16 },
+    /// `None` when the CPU has no machine check architecture to ask.
+    MachineCheck { mcg_status: Option<u64> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionReport {
+    pub exception: Exception,
+    pub instruction_pointer: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    pub error_code: Option<u64>,
+    pub detail: ExceptionDetail,
+}
+
+impl fmt::Display for ExceptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let exception = self.exception;
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            exception.name(),
+            exception.mnemonic(),
+            exception.vector()
+        )?;
+        writeln!(f, "  instruction pointer: {:#x}", self.instruction_pointer.as_u64())?;
+        write!(f, "  stack pointer:       {:#x}", self.stack_pointer.as_u64())?;
+        if let Some(error_code) = self.error_code {
+            write!(f, "\n  error code:          {:#x}", error_code)?;
+        }
+
+        match self.detail {
+            ExceptionDetail::None => Ok(()),
+            ExceptionDetail::Selector(selector) => write!(f, "\n  selector:            {}", selector),
+            ExceptionDetail::PageFault { accessed_address, error_code } => {
+                write!(f, " {:?}", error_code)?;
+                write!(f, "\n  accessed address:    {:#x}", accessed_address.as_u64())?;
+                write!(f, "\n  cause:               {}", page_fault_cause(error_code))?;
+                if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                    // The CPU found a reserved bit set while walking the page
+                    // tables, so the tables themselves are corrupt.
+                    write!(f, ", reserved bit set in a page table entry")?;
+                }
+                Ok(())
+            }
+            ExceptionDetail::Simd { mxcsr } => {
+                write!(f, "\n  mxcsr:               {:#x} (", mxcsr)?;
+                write_float_exceptions(f, mxcsr)?;
+                write!(f, ")")
+            }
+            ExceptionDetail::X87 { status_word } => {
+                write!(f, "\n  fpu status word:     {:#x} (", status_word)?;
+                write_float_exceptions(f, u32::from(status_word))?;
+                write!(f, ")")
+            }
+            ExceptionDetail::MachineCheck { mcg_status: Some(status) } => {
+                write!(f, "\n  mcg status:          {:#x}", status)?;
+                if status & 0b001 != 0 {
+                    write!(f, " restart-ip-valid")?;
+                }
+                if status & 0b010 != 0 {
+                    write!(f, " error-ip-valid")?;
+                }
+                if status & 0b100 != 0 {
+                    write!(f, " in-progress")?;
+                }
+                Ok(())
+            }
+            ExceptionDetail::MachineCheck { mcg_status: None } => {
+                write!(f, "\n  mcg status:          unavailable (no machine check architecture)")
+            }
+        }
+    }
+}
+
+/// Describes a page fault in words, like "write to a non-present page in kernel mode".
+pub fn page_fault_cause(error_code: PageFaultErrorCode) -> PageFaultCause {
+    PageFaultCause(error_code)
+}
+
+pub struct PageFaultCause(PageFaultErrorCode);
+
+impl fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.0.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if self.0.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.0.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a present page (protection violation)"
+        } else {
+            "a non-present page"
+        };
+        let mode = if self.0.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} {} in {} mode", access, page, mode)
+    }
+}
+
+// MXCSR and the x87 status word share the layout of their six exception flags.
+fn write_float_exceptions(f: &mut fmt::Formatter, flags: u32) -> fmt::Result {
+    const NAMES: [&str; 6] = [
+        "invalid operation",
+        "denormal operand",
+        "divide-by-zero",
+        "overflow",
+        "underflow",
+        "precision",
+    ];
+    let mut first = true;
+    for (bit, name) in NAMES.iter().enumerate() {
+        if flags & (1 << bit) != 0 {
+            if !first {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", name)?;
+            first = false;
+        }
+    }
+    if first {
+        write!(f, "no exception flags set")?;
+    }
+    Ok(())
+}
+
+// The last exception that was reported, kept so tests (and a debugger) can
+// look at it after the handler has panicked.
+static LAST_EXCEPTION: spin::Mutex<Option<ExceptionReport>> = spin::Mutex::new(None);
+
+pub fn last_exception() -> Option<ExceptionReport> {
+    *LAST_EXCEPTION.lock()
+}
+
+/// Registers every handler in this module.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    // These two get their own stacks: a page fault may be the kernel stack
+    // overflowing, and a machine check can arrive at any point at all.
+    // The indices are set up by `gdt::init`.
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+}
+
+fn report(
+    exception: Exception,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    detail: ExceptionDetail,
+) -> ! {
+    let report = ExceptionReport {
+        exception,
+        instruction_pointer: stack_frame.instruction_pointer,
+        stack_pointer: stack_frame.stack_pointer,
+        error_code,
+        detail,
+    };
+    *LAST_EXCEPTION.lock() = Some(report);
+    // We have no way to recover from any of these yet, so report it on the
+    // screen and over serial, where it's still readable when QEMU runs headless.
+    // `println!` writes to the log, so that's what needs to be on the screen.
+    Console::log().try_show();
+    println!("{}", report);
+    serial_println!("{}", report);
+    panic!(
+        "unrecoverable {} at {:#x}",
+        exception.mnemonic(),
+        report.instruction_pointer.as_u64()
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
+    report(Exception::DivideError, stack_frame, None, ExceptionDetail::None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    report(Exception::InvalidOpcode, stack_frame, None, ExceptionDetail::None);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
+    let status_word: u16;
+    unsafe { asm!("fnstsw ax", out("ax") status_word, options(nomem, nostack)) };
+    report(Exception::X87FloatingPoint, stack_frame, None, ExceptionDetail::X87 { status_word });
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let detail = ExceptionDetail::Selector(SelectorErrorCode(error_code));
+    report(Exception::SegmentNotPresent, stack_frame, Some(error_code), detail);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let detail = ExceptionDetail::Selector(SelectorErrorCode(error_code));
+    report(Exception::StackSegmentFault, stack_frame, Some(error_code), detail);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let detail = ExceptionDetail::Selector(SelectorErrorCode(error_code));
+    report(Exception::GeneralProtectionFault, stack_frame, Some(error_code), detail);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let detail = ExceptionDetail::PageFault {
+        accessed_address: Cr2::read(),
+        error_code,
+    };
+    report(Exception::PageFault, stack_frame, Some(error_code.bits()), detail);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    // The error code is always zero, it's only there for the stack layout.
+    report(Exception::AlignmentCheck, stack_frame, Some(error_code), ExceptionDetail::None);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    use core::arch::x86_64::__cpuid;
+    use x86_64::registers::model_specific::Msr;
+
+    const IA32_MCG_STATUS: u32 = 0x17a;
+    const CPUID_MCA: u32 = 1 << 14;
+    // Reading the MSR on a CPU without MCA would fault again.
+    let has_mca = unsafe { __cpuid(1) }.edx & CPUID_MCA != 0;
+    let mcg_status = if has_mca {
+        Some(unsafe { Msr::new(IA32_MCG_STATUS).read() })
+    } else {
+        None
+    };
+    report(Exception::MachineCheck, stack_frame, None, ExceptionDetail::MachineCheck { mcg_status });
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
+    let mut mxcsr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack)) };
+    report(Exception::SimdFloatingPoint, stack_frame, None, ExceptionDetail::Simd { mxcsr });
+}
+
+#[test_case]
+fn test_selector_error_code_decoding() {
+    let gdt = SelectorErrorCode(0x1230);
+    assert_eq!(gdt.table(), DescriptorTable::Gdt);
+    assert_eq!(gdt.index(), 0x246);
+    assert_eq!(alloc::format!("{}", gdt), "GDT index 582 (selector 0x1230)");
+
+    let idt = SelectorErrorCode((0x80 << 3) | 0b010);
+    assert_eq!(idt.table(), DescriptorTable::Idt);
+    assert_eq!(alloc::format!("{}", idt), "IDT vector 128");
+
+    let external_ldt = SelectorErrorCode(0b1101);
+    assert_eq!(external_ldt.table(), DescriptorTable::Ldt);
+    assert!(external_ldt.is_external());
+    assert_eq!(alloc::format!("{}", SelectorErrorCode(0)), "none");
+}
+
+#[test_case]
+fn test_page_fault_report_format() {
+    let report = ExceptionReport {
+        exception: Exception::PageFault,
+        instruction_pointer: VirtAddr::new(0x20_1234),
+        stack_pointer: VirtAddr::new(0x5000),
+        error_code: Some(0b110),
+        detail: ExceptionDetail::PageFault {
+            accessed_address: VirtAddr::new(0xdead_b000),
+            error_code: PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE,
+        },
+    };
+    let text = alloc::format!("{}", report);
+    assert!(text.starts_with("EXCEPTION: PAGE FAULT (#PF, vector 14)\n"));
+    assert!(text.contains("instruction pointer: 0x201234\n"));
+    assert!(text.contains("accessed address:    0xdeadb000\n"));
+    assert!(text.ends_with("cause:               write to a non-present page in user mode"));
+}
+
+#[test_case]
+fn test_float_exception_flags_format() {
+    let report = ExceptionReport {
+        exception: Exception::SimdFloatingPoint,
+        instruction_pointer: VirtAddr::new(0x1000),
+        stack_pointer: VirtAddr::new(0x2000),
+        error_code: None,
+        detail: ExceptionDetail::Simd { mxcsr: 0x1d84 | 0b1 },
+    };
+    let text = alloc::format!("{}", report);
+    assert!(!text.contains("error code"));
+    assert!(text.ends_with("mxcsr:               0x1d85 (invalid operation, divide-by-zero)"));
+}
@@ -1,53 +1,237 @@
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
-use lazy_static::lazy_static;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use spin::Once;
+use crate::memory::{self, PAGE_SIZE};
 
+// Indexes into the interrupt stack table of the TSS. An exception whose IDT
+// entry names one of these switches to that stack before its handler runs,
+// so it works even when the stack it interrupted is unusable.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0; // Define the index that will hold our double fault instruction.
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3; // a kernel stack overflow is a page fault, so it can't use the kernel stack.
+const IST_STACK_COUNT: usize = 4;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
+// The IST stacks live in their own stretch of virtual memory, each one right
+// above an unmapped guard page, and nothing else ever gets mapped here.
+pub const IST_STACKS_START: u64 = 0x_6000_0000_0000;
+
+/// Sizes of the interrupt stacks in bytes, each rounded up to whole pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IstStackSizes {
+    pub double_fault: u64,
+    pub nmi: u64,
+    pub machine_check: u64,
+    pub page_fault: u64,
+}
+
+impl Default for IstStackSizes {
+    fn default() -> IstStackSizes {
+        // The fault handlers format a report and then panic, which takes
+        // more stack than the handlers themselves.
+        IstStackSizes {
+            double_fault: 4 * PAGE_SIZE,
+            nmi: 2 * PAGE_SIZE,
+            machine_check: 4 * PAGE_SIZE,
+            page_fault: 4 * PAGE_SIZE,
+        }
+    }
+}
+
+// The biggest stack the command line can ask for, so a stray zero can't
+// use up the frames everything after us needs.
+const MAX_STACK_KIB: u64 = 1024;
+
+impl IstStackSizes {
+    /// The default sizes, with any of them changed by the
+    /// `double_fault_stack=`, `nmi_stack=`, `machine_check_stack=` and
+    /// `page_fault_stack=` command line options, given in KiB.
+    pub fn from_cmdline() -> IstStackSizes {
+        let defaults = IstStackSizes::default();
+        IstStackSizes {
+            double_fault: size_option("double_fault_stack", defaults.double_fault),
+            nmi: size_option("nmi_stack", defaults.nmi),
+            machine_check: size_option("machine_check_stack", defaults.machine_check),
+            page_fault: size_option("page_fault_stack", defaults.page_fault),
+        }
+    }
+
+    fn by_index(&self) -> [u64; IST_STACK_COUNT] {
+        let mut sizes = [0; IST_STACK_COUNT];
+        sizes[DOUBLE_FAULT_IST_INDEX as usize] = self.double_fault;
+        sizes[NMI_IST_INDEX as usize] = self.nmi;
+        sizes[MACHINE_CHECK_IST_INDEX as usize] = self.machine_check;
+        sizes[PAGE_FAULT_IST_INDEX as usize] = self.page_fault;
+        sizes
+    }
+}
+
+fn size_option(key: &str, default: u64) -> u64 {
+    match crate::cmdline::get(key) {
+        None => default,
+        Some(value) => parse_kib(value).unwrap_or_else(|| {
+            crate::println!(
+                "{}={}: not a size from 1 to {} KiB, keeping {} KiB",
+                key,
+                value,
+                MAX_STACK_KIB,
+                default / 1024
+            );
+            default
+        }),
+    }
+}
+
+// A stack size in KiB, turned into bytes.
+fn parse_kib(value: &str) -> Option<u64> {
+    match value.parse() {
+        Ok(kib) if kib > 0 && kib <= MAX_STACK_KIB => Some(kib * 1024),
+        _ => None,
+    }
+}
+
+/// One interrupt stack, spanning `bottom..top`, with the unmapped guard page
+/// right below it that turns an overflow into a page fault instead of
+/// silently overwriting whatever comes next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IstStack {
+    pub guard_page: Page,
+    pub bottom: VirtAddr,
+    pub top: VirtAddr, // stacks grow down, so this is what goes into the TSS.
+}
+
+impl IstStack {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    pub fn guard_page_contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.guard_page.start_address() && addr < self.bottom
+    }
+}
+
+static IST_STACKS: Once<[IstStack; IST_STACK_COUNT]> = Once::new();
+// The TSS has to be built at runtime now, since its stacks come from the
+// frame allocator, so these are `Once`s rather than `lazy_static`s.
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+
+struct Selectors {
+    code_selector: SegmentSelector,
+    tss_selector: SegmentSelector
+}
+
+// Maps the interrupt stacks one after the other, each with its guard page
+// left unmapped below it.
+fn allocate_ist_stacks(sizes: IstStackSizes) -> [IstStack; IST_STACK_COUNT] {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut page_table = memory::paging::kernel_page_table();
+    let mut next = Page::containing_address(VirtAddr::new(IST_STACKS_START));
+    let mut stacks = [IstStack {
+        guard_page: next,
+        bottom: VirtAddr::new(IST_STACKS_START),
+        top: VirtAddr::new(IST_STACKS_START),
+    }; IST_STACK_COUNT];
+
+    for (stack, size) in stacks.iter_mut().zip(sizes.by_index().iter()) {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        assert!(pages > 0, "interrupt stacks need at least one page");
+        // The guard page is simply never mapped.
+        let guard_page = next;
+        let bottom = guard_page + 1;
+        let top = bottom + pages;
+        page_table
+            .map_range(Page::range(bottom, top), flags)
+            .expect("mapping an interrupt stack failed");
+        *stack = IstStack {
+            guard_page,
+            bottom: bottom.start_address(),
+            top: top.start_address(),
+        };
+        next = top;
+    }
+    stacks
+}
+
+/// Maps the interrupt stacks, sized by `IstStackSizes::from_cmdline`, then
+/// builds and loads the TSS and the GDT. Needs `memory::init` to have run.
+pub fn init() {
+    init_with(IstStackSizes::from_cmdline());
+}
+
+/// Like `init`, but with interrupt stacks of the given sizes. Only the first
+/// call sets things up, later calls just load the same tables again.
+pub fn init_with(sizes: IstStackSizes) {
+    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::tables::load_tss;
+
+    let stacks = IST_STACKS.call_once(|| allocate_ist_stacks(sizes));
+    let tss = TSS.call_once(|| {
         // An x86 structure that holds information about a task,
         // for consumption vy the kernel, such as register state,
         // I/O permissions, and stack pointers.
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = { // We specifically target the index containing the double fault exception handler.
-            const STACK_SIZE: usize = 4096; // initialize our stack with some memory.
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        for (index, stack) in stacks.iter().enumerate() {
+            tss.interrupt_stack_table[index] = stack.top;
+        }
         tss
-    };
-}
-
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    });
+    let (gdt, selectors) = GDT.call_once(|| {
         // A GDT is a structure used by x86 processors to define
         // characteristics of memory areas used during the lifetime
         // of a program.
         // GDT entries can be TSS's.
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector})
-    };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        (gdt, Selectors { code_selector, tss_selector })
+    });
+
+    gdt.load();
+    unsafe {
+        set_cs(selectors.code_selector);
+        load_tss(selectors.tss_selector);
+    }
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector
+/// The interrupt stack at `index` of the interrupt stack table, once `init` has run.
+pub fn ist_stack(index: u16) -> Option<IstStack> {
+    IST_STACKS.wait().and_then(|stacks| stacks.get(index as usize).copied())
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
-    GDT.0.load();
-    unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+#[test_case]
+fn test_ist_stacks_have_guard_pages() {
+    let page_table = memory::paging::kernel_page_table();
+    let sizes = IstStackSizes::from_cmdline().by_index();
+    for index in 0..IST_STACK_COUNT as u16 {
+        let stack = ist_stack(index).expect("gdt::init has not been called");
+        let pages = (sizes[index as usize] + PAGE_SIZE - 1) / PAGE_SIZE;
+        assert_eq!(stack.size(), pages * PAGE_SIZE);
+        assert_eq!(stack.top.as_u64() % 16, 0);
+        assert!(!page_table.is_mapped(stack.guard_page.start_address()));
+        assert!(page_table.is_mapped(stack.bottom));
+        assert!(page_table.is_mapped(stack.top - 1u64));
+        assert!(stack.guard_page_contains(stack.bottom - 1u64));
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_ist_stacks_are_in_the_tss() {
+    let tss = TSS.wait().expect("gdt::init has not been called");
+    assert_eq!(
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize],
+        ist_stack(PAGE_FAULT_IST_INDEX).unwrap().top
+    );
+}
+
+#[test_case]
+fn test_parse_stack_sizes() {
+    assert_eq!(parse_kib("16"), Some(16 * 1024));
+    assert_eq!(parse_kib("1024"), Some(MAX_STACK_KIB * 1024));
+    assert_eq!(parse_kib("0"), None);
+    assert_eq!(parse_kib("1025"), None);
+    assert_eq!(parse_kib("16k"), None);
+}
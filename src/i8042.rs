@@ -0,0 +1,463 @@
+use crate::keyboard::ScancodeSet;
+use spin::Once;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+// The i8042 PS/2 controller, with the keyboard on its first port and, if it
+// has one, usually a mouse on the second. The firmware leaves it in some
+// state or other, so `init` resets it and the devices before we rely on it.
+//
+// Port 0x60 carries data both ways, reading port 0x64 gives the status and
+// writing it sends a command to the controller itself.
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_SECOND_PORT: u8 = 1 << 5; // the byte in the output buffer is from the second port
+
+// Controller commands.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xa7;
+const ENABLE_SECOND: u8 = 0xa8;
+const TEST_SECOND: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST: u8 = 0xab;
+const DISABLE_FIRST: u8 = 0xad;
+const ENABLE_FIRST: u8 = 0xae;
+const WRITE_SECOND: u8 = 0xd4; // the next data byte goes to the second port
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Bits of the configuration byte.
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6; // turn scancode set 2 into set 1
+
+// Commands every PS/2 device understands, and its replies.
+const DEVICE_SET_LEDS: u8 = 0xed;
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_SET_TYPEMATIC: u8 = 0xf3;
+const DEVICE_SET_SCANCODE_SET: u8 = 0xf0; // keyboards only
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+
+// How many times we read the status port before giving up on a byte. Each
+// read takes about a microsecond, and the controller answers in well under
+// a second even when a device is resetting.
+const TIMEOUT_POLLS: u32 = 1_000_000;
+// Devices that have nothing more to say, like an AT keyboard asked for its
+// ID, are given a few milliseconds.
+const SHORT_TIMEOUT_POLLS: u32 = 10_000;
+const RESENDS: usize = 3;
+
+static INFO: Once<ControllerInfo> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I8042Error {
+    /// The controller didn't take or give a byte in time, there may not
+    /// be one at all.
+    Timeout,
+    SelfTestFailed(u8),
+    /// A device answered a command with something other than ACK.
+    UnexpectedReply(u8),
+    /// A device kept asking for the command again.
+    Resend,
+    /// No keyboard was found by `init`.
+    NoKeyboard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+/// What a device said it is when asked to identify itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    /// An old AT keyboard, which doesn't answer at all.
+    AtKeyboard,
+    Mf2Keyboard,
+    StandardMouse,
+    /// An IntelliMouse, with a scroll wheel.
+    ScrollMouse,
+    /// An IntelliMouse with a wheel and five buttons.
+    FiveButtonMouse,
+    Other(u8, u8),
+}
+
+impl DeviceType {
+    fn from_id(id: &[u8]) -> DeviceType {
+        match id {
+            [] => DeviceType::AtKeyboard,
+            // The second byte is 0x83, unless the controller translates
+            // it like a scancode.
+            [0xab, 0x83] | [0xab, 0x41] | [0xab, 0xc1] => DeviceType::Mf2Keyboard,
+            [0x00] => DeviceType::StandardMouse,
+            [0x03] => DeviceType::ScrollMouse,
+            [0x04] => DeviceType::FiveButtonMouse,
+            [first] => DeviceType::Other(*first, 0),
+            [first, second, ..] => DeviceType::Other(*first, *second),
+        }
+    }
+
+    pub fn is_keyboard(self) -> bool {
+        match self {
+            DeviceType::AtKeyboard | DeviceType::Mf2Keyboard => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_mouse(self) -> bool {
+        match self {
+            DeviceType::StandardMouse | DeviceType::ScrollMouse | DeviceType::FiveButtonMouse => true,
+            _ => false,
+        }
+    }
+}
+
+/// What `init` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    pub dual_port: bool,
+    /// The device on each port, `None` when the port failed its test or
+    /// nothing answered a reset.
+    pub first_port: Option<DeviceType>,
+    pub second_port: Option<DeviceType>,
+}
+
+impl ControllerInfo {
+    /// The port the keyboard is plugged into.
+    pub fn keyboard_port(&self) -> Option<Ps2Port> {
+        self.port_where(DeviceType::is_keyboard)
+    }
+
+    /// The port the mouse is plugged into.
+    pub fn mouse_port(&self) -> Option<Ps2Port> {
+        self.port_where(DeviceType::is_mouse)
+    }
+
+    fn port_where(&self, predicate: fn(DeviceType) -> bool) -> Option<Ps2Port> {
+        if self.first_port.map_or(false, predicate) {
+            Some(Ps2Port::First)
+        } else if self.second_port.map_or(false, predicate) {
+            Some(Ps2Port::Second)
+        } else {
+            None
+        }
+    }
+}
+
+/// Which of the keyboard's lock LEDs are lit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn bits(self) -> u8 {
+        self.scroll_lock as u8 | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+fn status() -> u8 {
+    let mut port: Port<u8> = Port::new(STATUS_PORT);
+    unsafe { port.read() }
+}
+
+fn write_command(command: u8) -> Result<(), I8042Error> {
+    wait_for_input_empty()?;
+    let mut port: Port<u8> = Port::new(COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), I8042Error> {
+    wait_for_input_empty()?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+fn wait_for_input_empty() -> Result<(), I8042Error> {
+    (0..TIMEOUT_POLLS)
+        .find(|_| status() & STATUS_INPUT_FULL == 0)
+        .map(|_| ())
+        .ok_or(I8042Error::Timeout)
+}
+
+fn read_data_within(polls: u32) -> Result<u8, I8042Error> {
+    (0..polls)
+        .find(|_| status() & STATUS_OUTPUT_FULL != 0)
+        .ok_or(I8042Error::Timeout)?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    Ok(unsafe { port.read() })
+}
+
+fn read_data() -> Result<u8, I8042Error> {
+    read_data_within(TIMEOUT_POLLS)
+}
+
+// Throws away whatever the devices still have to say.
+fn drain() {
+    while read_data_within(SHORT_TIMEOUT_POLLS).is_ok() {}
+}
+
+fn command_with_reply(command: u8) -> Result<u8, I8042Error> {
+    write_command(command)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), I8042Error> {
+    write_command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Sends `byte` to the device on `port` and waits for its ACK, sending it
+/// again when the device asks for that.
+fn send_to_device(port: Ps2Port, byte: u8) -> Result<(), I8042Error> {
+    for _ in 0..RESENDS {
+        if port == Ps2Port::Second {
+            write_command(WRITE_SECOND)?;
+        }
+        write_data(byte)?;
+        match read_data()? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            reply => return Err(I8042Error::UnexpectedReply(reply)),
+        }
+    }
+    Err(I8042Error::Resend)
+}
+
+/// The byte waiting in the output buffer and the port it came from, for
+/// the IRQ handlers. `None` when there is nothing to read, like when polling
+/// in `init` or `set_leds` took the byte the interrupt was raised for.
+pub(crate) fn read_output() -> Option<(Ps2Port, u8)> {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+    let port = if status & STATUS_SECOND_PORT != 0 { Ps2Port::Second } else { Ps2Port::First };
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    Some((port, unsafe { data.read() }))
+}
+
+/// Resets the controller and whatever is plugged into it, finds out what
+/// that is, and turns the IRQs back on for the ports that work.
+///
+/// Leaves translation to scancode set 1 on, which is what the keyboard
+/// decoder expects unless told otherwise.
+pub fn init() -> Result<&'static ControllerInfo, I8042Error> {
+    let info = without_interrupts(probe)?;
+    Ok(INFO.call_once(|| info))
+}
+
+fn probe() -> Result<ControllerInfo, I8042Error> {
+    // Keep the devices quiet while we set things up.
+    write_command(DISABLE_FIRST)?;
+    write_command(DISABLE_SECOND)?;
+    drain();
+
+    let mut config = command_with_reply(READ_CONFIG)?;
+    // With the second port disabled its clock should be off too, if it's
+    // still on there is no second port.
+    let maybe_dual_port = config & CONFIG_SECOND_CLOCK_DISABLED != 0;
+    config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    match command_with_reply(SELF_TEST)? {
+        SELF_TEST_PASSED => {}
+        reply => return Err(I8042Error::SelfTestFailed(reply)),
+    }
+    // Some controllers reset themselves during the self-test.
+    write_config(config)?;
+
+    let dual_port = maybe_dual_port && {
+        write_command(ENABLE_SECOND)?;
+        let enabled = command_with_reply(READ_CONFIG)? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        write_command(DISABLE_SECOND)?;
+        enabled
+    };
+
+    let first_works = command_with_reply(TEST_FIRST)? == PORT_TEST_PASSED;
+    let second_works = dual_port && command_with_reply(TEST_SECOND)? == PORT_TEST_PASSED;
+
+    let mut first_port = None;
+    if first_works {
+        write_command(ENABLE_FIRST)?;
+        config = (config | CONFIG_FIRST_IRQ) & !CONFIG_FIRST_CLOCK_DISABLED;
+        first_port = reset_and_identify(Ps2Port::First);
+    }
+    let mut second_port = None;
+    if second_works {
+        write_command(ENABLE_SECOND)?;
+        config = (config | CONFIG_SECOND_IRQ) & !CONFIG_SECOND_CLOCK_DISABLED;
+        second_port = reset_and_identify(Ps2Port::Second);
+    }
+
+    drain();
+    write_config(config)?;
+    Ok(ControllerInfo { dual_port, first_port, second_port })
+}
+
+fn reset_and_identify(port: Ps2Port) -> Option<DeviceType> {
+    send_to_device(port, DEVICE_RESET).ok()?;
+    if read_data().ok()? != DEVICE_SELF_TEST_PASSED {
+        return None;
+    }
+    drain(); // mice send their ID after a reset
+    let device = identify_device(port).ok()?;
+    send_to_device(port, DEVICE_ENABLE_SCANNING).ok()?;
+    Some(device)
+}
+
+// Asks the device on `port` what it is, and leaves scanning off.
+fn identify_device(port: Ps2Port) -> Result<DeviceType, I8042Error> {
+    // Scanning has to be off, or a key press could end up in the ID.
+    send_to_device(port, DEVICE_DISABLE_SCANNING)?;
+    send_to_device(port, DEVICE_IDENTIFY)?;
+    let mut id = [0u8; 2];
+    let mut length = 0;
+    while length < id.len() {
+        match read_data_within(SHORT_TIMEOUT_POLLS) {
+            Ok(byte) => id[length] = byte,
+            Err(_) => break,
+        }
+        length += 1;
+    }
+    Ok(DeviceType::from_id(&id[..length]))
+}
+
+/// What `init` found, if it succeeded.
+pub fn info() -> Option<&'static ControllerInfo> {
+    INFO.wait()
+}
+
+/// Sends `bytes` to the device on `port`, waiting for its ACK after each.
+/// Polls for the replies with interrupts off, so the IRQ handlers don't
+/// take them for input.
+pub fn send_command(port: Ps2Port, bytes: &[u8]) -> Result<(), I8042Error> {
+    without_interrupts(|| bytes.iter().try_for_each(|&byte| send_to_device(port, byte)))
+}
+
+/// Asks the device on `port` what it is. Leaves it with scanning, or data
+/// reporting for a mouse, switched off.
+pub fn identify(port: Ps2Port) -> Result<DeviceType, I8042Error> {
+    without_interrupts(|| identify_device(port))
+}
+
+fn keyboard_command(bytes: &[u8]) -> Result<(), I8042Error> {
+    let port = info().and_then(ControllerInfo::keyboard_port).ok_or(I8042Error::NoKeyboard)?;
+    send_command(port, bytes)
+}
+
+/// Lights up the keyboard's lock LEDs. The keyboard doesn't do that by
+/// itself, whoever tracks Caps Lock and friends has to.
+pub fn set_leds(leds: Leds) -> Result<(), I8042Error> {
+    keyboard_command(&[DEVICE_SET_LEDS, leds.bits()])
+}
+
+/// Makes the keyboard's bytes reach us as `scancode_set`.
+///
+/// Keyboards are left sending set 2, and on the first port the controller
+/// turns that into set 1 when translation is on, so there it's only the
+/// translation bit that changes. Nothing translates for the second port,
+/// so a keyboard there is asked for the set itself.
+pub fn set_scancode_set(scancode_set: ScancodeSet) -> Result<(), I8042Error> {
+    let port = info().and_then(ControllerInfo::keyboard_port).ok_or(I8042Error::NoKeyboard)?;
+    without_interrupts(|| {
+        // A key pressed halfway through would arrive in neither set.
+        send_to_device(port, DEVICE_DISABLE_SCANNING)?;
+        let keyboard_set = match (port, scancode_set) {
+            (Ps2Port::First, _) => {
+                let config = command_with_reply(READ_CONFIG)?;
+                write_config(match scancode_set {
+                    ScancodeSet::Set1 => config | CONFIG_TRANSLATION,
+                    ScancodeSet::Set2 => config & !CONFIG_TRANSLATION,
+                })?;
+                2
+            }
+            (Ps2Port::Second, ScancodeSet::Set1) => 1,
+            (Ps2Port::Second, ScancodeSet::Set2) => 2,
+        };
+        send_to_device(port, DEVICE_SET_SCANCODE_SET)?;
+        send_to_device(port, keyboard_set)?;
+        drain();
+        send_to_device(port, DEVICE_ENABLE_SCANNING)
+    })
+}
+
+/// Makes a held key repeat after `delay_ms` (250 to 1000 ms), at
+/// `chars_per_second` (2 to 30) times a second. Both are rounded to the
+/// nearest setting the keyboard has.
+pub fn set_typematic(delay_ms: u32, chars_per_second: u32) -> Result<(), I8042Error> {
+    keyboard_command(&[DEVICE_SET_TYPEMATIC, typematic_byte(delay_ms, chars_per_second)])
+}
+
+// The delay is in steps of 250 ms in bits 5 and 6. The rate in bits 0 to 4
+// encodes a repeat period of (8 + bits 0..3) * 2^(bits 3..5) * 4.17 ms.
+fn typematic_byte(delay_ms: u32, chars_per_second: u32) -> u8 {
+    let delay = ((delay_ms + 125) / 250).max(1).min(4) - 1;
+    let period_us = 1_000_000 / chars_per_second.max(1);
+    let rate = (0..32u32)
+        .min_by_key(|rate| {
+            let rate_period_us = (8 + (rate & 7)) * (1 << (rate >> 3)) * 4167;
+            (rate_period_us as i64 - period_us as i64).abs()
+        })
+        .unwrap();
+    (delay << 5 | rate) as u8
+}
+
+#[test_case]
+fn test_device_ids() {
+    assert_eq!(DeviceType::from_id(&[]), DeviceType::AtKeyboard);
+    assert_eq!(DeviceType::from_id(&[0xab, 0x41]), DeviceType::Mf2Keyboard);
+    assert_eq!(DeviceType::from_id(&[0x03]), DeviceType::ScrollMouse);
+    assert_eq!(DeviceType::from_id(&[0x12, 0x34]), DeviceType::Other(0x12, 0x34));
+    assert!(DeviceType::Mf2Keyboard.is_keyboard() && !DeviceType::Mf2Keyboard.is_mouse());
+}
+
+#[test_case]
+fn test_typematic_byte() {
+    assert_eq!(typematic_byte(250, 30), 0x00);
+    assert_eq!(typematic_byte(1000, 2), 0x7f);
+    assert_eq!(typematic_byte(500, 10), 0x2c);
+    // Out of range values are clamped.
+    assert_eq!(typematic_byte(0, 100), 0x00);
+}
+
+#[test_case]
+fn test_controller_found_a_keyboard() {
+    // QEMU emulates an i8042 with a keyboard and a mouse on it.
+    let info = info().expect("no PS/2 controller");
+    assert_eq!(info.keyboard_port(), Some(Ps2Port::First));
+    assert_eq!(set_leds(Leds { caps_lock: true, ..Leds::default() }), Ok(()));
+    assert_eq!(set_leds(Leds::default()), Ok(()));
+    assert_eq!(set_typematic(500, 10), Ok(()));
+}
+
+#[test_case]
+fn test_switching_scancode_sets_reprograms_the_controller() {
+    let translation = || {
+        without_interrupts(|| command_with_reply(READ_CONFIG)).map(|config| config & CONFIG_TRANSLATION != 0)
+    };
+    assert_eq!(set_scancode_set(ScancodeSet::Set2), Ok(()));
+    assert_eq!(translation(), Ok(false));
+    assert_eq!(set_scancode_set(ScancodeSet::Set1), Ok(()));
+    assert_eq!(translation(), Ok(true));
+}
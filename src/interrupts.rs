@@ -1,10 +1,14 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use crate::println; // locally defined println
+use crate::exceptions; // handlers for the remaining CPU exceptions.
+use crate::apic; // the local and I/O APICs, used instead of the PICs when present.
 use crate::gdt; // local gdt module.
-use crate::print; // locally defined print.
+use crate::i8042::{self, Ps2Port}; // the PS/2 controller the keyboard is plugged into.
 use spin;
 use pic8259_simple::ChainedPics; // Allows us to handle hardware interrupts
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -17,8 +21,9 @@ impl InterruptIndex {
         self as u8
     }
     
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// The IRQ line behind this vector.
+    pub fn irq_line(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
@@ -36,67 +41,292 @@ lazy_static! {
         // the x86_84 intel architecture.
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        exceptions::install(&mut idt);
+        apic::install(&mut idt);
+        // Every IRQ vector goes through the dispatcher, drivers hook in
+        // with `register_irq` instead of editing this table.
+        for (line, stub) in IRQ_STUBS.iter().enumerate() {
+            idt[irq_vector(line as u8) as usize].set_handler_fn(*stub);
+        }
         unsafe {
                 idt.double_fault
                     .set_handler_fn(double_fault_handler)
                     .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+                // An NMI can interrupt anything, even code that is in the
+                // middle of switching stacks, so it gets a stack of its own.
+                idt.non_maskable_interrupt
+                    .set_handler_fn(nmi_handler)
+                    .set_stack_index(gdt::NMI_IST_INDEX);
             }
         idt
     };
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame
-)
-{   use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use x86_64::instructions::port::Port;
-    use spin::Mutex;
+/// Which interrupt controller delivers the IRQs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The legacy pair of 8259 PICs, for machines without an APIC.
+    Pic,
+    /// The local APIC and the I/O APICs, with the 8259s masked.
+    Apic,
+}
+
+static CONTROLLER: spin::Once<InterruptController> = spin::Once::new();
+
+/// The controller `init_irqs` picked, the PICs until it has run.
+pub fn interrupt_controller() -> InterruptController {
+    CONTROLLER.wait().copied().unwrap_or(InterruptController::Pic)
+}
+
+/// Number of IRQ lines on the two chained PICs.
+pub const IRQ_LINES: u8 = 16;
+
+/// A driver's interrupt handler, called with the IRQ line that fired.
+///
+/// Handlers run in interrupt context with interrupts disabled, so they must
+/// not block or take locks that non-interrupt code holds with interrupts
+/// enabled. End of interrupt is sent by the dispatcher once they return.
+pub type IrqHandler = fn(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// There are only lines 0 to 15.
+    InvalidLine(u8),
+    /// Another handler already owns the line.
+    AlreadyRegistered(u8),
+}
+
+// One slot per line holding the handler's address, or 0 when the line is
+// free. Atomics rather than a lock, so the dispatcher never has to wait on
+// code it interrupted.
+static IRQ_HANDLERS: [AtomicUsize; IRQ_LINES as usize] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+static SPURIOUS_IRQ7: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_IRQ15: AtomicU64 = AtomicU64::new(0);
+
+/// The interrupt vector IRQ `line` is delivered on.
+pub fn irq_vector(line: u8) -> u8 {
+    PIC_1_OFFSET + line
+}
+
+/// Installs `handler` for IRQ `line` and unmasks the line.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if line >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+    IRQ_HANDLERS[line as usize]
+        .compare_exchange(0, handler as usize, Ordering::SeqCst, Ordering::SeqCst)
+        .map_err(|_| IrqError::AlreadyRegistered(line))?;
+    set_irq_masked(line, false);
+    Ok(())
+}
+
+/// Masks IRQ `line` and removes its handler, returning the handler if
+/// there was one.
+pub fn unregister_irq(line: u8) -> Option<IrqHandler> {
+    if line >= IRQ_LINES {
+        return None;
+    }
+    set_irq_masked(line, true);
+    match IRQ_HANDLERS[line as usize].swap(0, Ordering::SeqCst) {
+        0 => None,
+        // Only ever stored from an `IrqHandler` in `register_irq`.
+        raw => Some(unsafe { core::mem::transmute::<usize, IrqHandler>(raw) }),
+    }
+}
 
-    lazy_static! { // We create a interior mutable keyboard structure in order to safely
-                  // read events/data from a specified port.
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, 
-                                     ScancodeSet1, 
-                                     HandleControl::Ignore));
+/// How many spurious interrupts have arrived on `line`, only lines 7 and 15
+/// can have any.
+pub fn spurious_irq_count(line: u8) -> u64 {
+    match line {
+        7 => SPURIOUS_IRQ7.load(Ordering::Relaxed),
+        15 => SPURIOUS_IRQ15.load(Ordering::Relaxed),
+        _ => 0,
     }
+}
+
+// Ports of the master and slave PIC, `ChainedPics` doesn't expose the
+// registers we need for masking and spurious interrupt detection.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_2_DATA: u16 = 0xa1;
+const PIC_READ_ISR: u8 = 0x0b; // OCW3: the next command port read returns the in-service register
+const PIC_EOI: u8 = 0x20;
+const CASCADE_LINE: u8 = 2; // the line the slave PIC is wired to on the master
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+fn set_irq_masked(line: u8, masked: bool) {
+    use x86_64::instructions::interrupts::without_interrupts;
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key)
+    if interrupt_controller() == InterruptController::Apic {
+        apic::set_irq_masked(line, masked);
+        return;
+    }
+    without_interrupts(|| {
+        // Held so nobody else talks to the PICs halfway through our
+        // read-modify-write of the mask.
+        let _pics = PICS.lock();
+        let (port, bit) = if line < 8 { (PIC_1_DATA, line) } else { (PIC_2_DATA, line - 8) };
+        let mut data: Port<u8> = Port::new(port);
+        unsafe {
+            let mask = data.read();
+            data.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+            // Slave lines only get through when the cascade line is open.
+            if line >= 8 && !masked {
+                let mut master: Port<u8> = Port::new(PIC_1_DATA);
+                let mask = master.read();
+                master.write(mask & !(1 << CASCADE_LINE));
             }
         }
-    }
-    
+    });
+}
+
+// A PIC raises its lowest priority line (7 on either chip) when a request
+// goes away before the CPU acknowledged it. The in-service bit is not set for
+// such a spurious interrupt, which is how we tell it apart.
+fn is_spurious(line: u8) -> bool {
+    let command = match line {
+        7 => PIC_1_COMMAND,
+        15 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let mut port: Port<u8> = Port::new(command);
+    let isr = unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
+    };
+    isr & 0x80 == 0
+}
+
+// With the APIC in charge the PICs are masked, and it has a spurious vector
+// of its own.
+fn mask_pics() {
+    let _pics = PICS.lock();
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
+        Port::<u8>::new(PIC_1_DATA).write(0xff);
+        Port::<u8>::new(PIC_2_DATA).write(0xff);
     }
 }
-extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+
+fn dispatch(line: u8) {
+    let controller = interrupt_controller();
+    if controller == InterruptController::Pic && is_spurious(line) {
+        if line == 15 {
+            SPURIOUS_IRQ15.fetch_add(1, Ordering::Relaxed);
+            // The master did see a real interrupt on the cascade line, only
+            // the slave must not get an EOI.
+            unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+        } else {
+            SPURIOUS_IRQ7.fetch_add(1, Ordering::Relaxed);
+        }
+        return;
+    }
+
+    let raw = IRQ_HANDLERS[line as usize].load(Ordering::SeqCst);
+    if raw != 0 {
+        // Only ever stored from an `IrqHandler` in `register_irq`.
+        let handler = unsafe { core::mem::transmute::<usize, IrqHandler>(raw) };
+        handler(line);
+    }
+    match controller {
+        InterruptController::Apic => apic::end_of_interrupt(),
+        // `ChainedPics` sends the EOI to the slave as well for lines 8-15.
+        InterruptController::Pic => unsafe {
+            PICS.lock().notify_end_of_interrupt(irq_vector(line));
+        },
+    }
+}
+
+// The IDT needs one function per vector, and each of them only has to tell
+// the dispatcher which line it is for.
+macro_rules! irq_stubs {
+    ($($name:ident => $line:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                dispatch($line);
+            }
+        )*
+
+        const IRQ_STUBS: [extern "x86-interrupt" fn(&mut InterruptStackFrame); IRQ_LINES as usize] =
+            [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3,
+    irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11,
+    irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+}
+
+fn keyboard_irq(_line: u8) {
+    // Just queue the byte, `keyboard::read_key` decodes it later. Printing
+    // from here could deadlock on a writer the interrupted code holds.
+    if let Some((Ps2Port::First, scancode)) = i8042::read_output() {
+        crate::keyboard::add_scancode(scancode);
     }
 }
+
+fn timer_irq(_line: u8) {
+    crate::time::tick();
+}
+
 pub fn init_idt() {
     IDT.load();
 }
 
+/// Sets up the interrupt controller, the APIC when there is one and the
+/// 8259 PICs otherwise, then hooks up the timer and the keyboard.
+/// `irqchip=pic` on the kernel command line picks the PICs regardless.
+/// Needs `acpi::init` to have run.
+pub fn init_irqs() {
+    // Remapped even when the APIC takes over, so a stray interrupt from the
+    // masked PICs can't land on an exception vector.
+    unsafe { PICS.lock().initialize() };
+    let force_pic = match crate::cmdline::get("irqchip") {
+        None | Some("apic") => false,
+        Some("pic") => true,
+        Some(other) => {
+            println!("irqchip={}: unknown interrupt controller, using the APIC if there is one", other);
+            false
+        }
+    };
+    let controller = if force_pic {
+        InterruptController::Pic
+    } else {
+        match apic::init(PIC_1_OFFSET) {
+            Ok(()) => {
+                mask_pics();
+                InterruptController::Apic
+            }
+            Err(_) => InterruptController::Pic,
+        }
+    };
+    CONTROLLER.call_once(|| controller);
+
+    register_irq(InterruptIndex::Timer.irq_line(), timer_irq).expect("timer IRQ is already taken");
+    register_irq(InterruptIndex::Keyboard.irq_line(), keyboard_irq).expect("keyboard IRQ is already taken");
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: &mut InterruptStackFrame)
 {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: &mut InterruptStackFrame)
+{
+    // Usually a hardware failure or a watchdog, neither of which we can do
+    // anything about, so just report it and carry on.
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame, 
     _error_code: u64
@@ -107,4 +337,53 @@ extern "x86-interrupt" fn double_fault_handler(
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+static TEST_IRQ_CALLS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+fn test_irq_handler(line: u8) {
+    assert_eq!(line, 10);
+    TEST_IRQ_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn test_register_and_unregister_irq() {
+    assert_eq!(register_irq(10, test_irq_handler), Ok(()));
+    assert_eq!(register_irq(10, test_irq_handler), Err(IrqError::AlreadyRegistered(10)));
+    assert!(unregister_irq(10).is_some());
+    assert!(unregister_irq(10).is_none());
+    assert_eq!(register_irq(IRQ_LINES, test_irq_handler), Err(IrqError::InvalidLine(16)));
+}
+
+#[test_case]
+fn test_irq_is_dispatched_to_handler() {
+    register_irq(10, test_irq_handler).unwrap();
+    let calls = TEST_IRQ_CALLS.load(Ordering::SeqCst);
+    unsafe { asm!("int 0x2a", options(nomem, nostack)) }; // vector 32 + 10
+    assert_eq!(TEST_IRQ_CALLS.load(Ordering::SeqCst), calls + 1);
+    unregister_irq(10);
+}
+
+#[test_case]
+fn test_interrupt_controller_is_chosen() {
+    let expected = if apic::is_enabled() { InterruptController::Apic } else { InterruptController::Pic };
+    assert_eq!(interrupt_controller(), expected);
+}
+
+#[test_case]
+fn test_spurious_irqs_are_counted() {
+    if interrupt_controller() != InterruptController::Pic {
+        // Only the 8259s have spurious IRQs on 7 and 15, tests/pic_fallback.rs
+        // boots with them to check this.
+        return;
+    }
+    // Raised in software, so the PICs have nothing in service and both look
+    // exactly like spurious interrupts.
+    let irq7 = spurious_irq_count(7);
+    let irq15 = spurious_irq_count(15);
+    unsafe { asm!("int 0x27", "int 0x2f", options(nomem, nostack)) };
+    assert_eq!(spurious_irq_count(7), irq7 + 1);
+    assert_eq!(spurious_irq_count(15), irq15 + 1);
+}
@@ -0,0 +1,612 @@
+use crate::i8042::I8042Error;
+use crate::queue::IrqQueue;
+use crate::vga_buffer::Console;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1, ScancodeSet2};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The keyboard interrupt only reads the scancode and queues it, decoding it
+// into keys happens here, outside interrupt context, whenever someone asks
+// for a key. That way the handler never takes a lock, and keys can go to
+// whoever wants them rather than straight to the screen.
+//
+// Shift+PageUp and Shift+PageDown are the exception: they page through the
+// scrollback from the interrupt handler itself, so they work with nobody
+// reading keys, like after a panic.
+
+static SCANCODES: IrqQueue<u8> = IrqQueue::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// The task waiting on a `ScancodeStream` or `KeyStream`, if any.
+static WAKER: AtomicWaker = AtomicWaker::new();
+static SCROLL_KEYS: ScrollKeys = ScrollKeys::new();
+// The `ScancodeSet` the decoder was last set up for, for `SCROLL_KEYS`,
+// which can't take the decoder's lock.
+static SCANCODE_SET: AtomicU8 = AtomicU8::new(ScancodeSet::Set1 as u8);
+
+lazy_static! {
+    // Ctrl combinations come out as control characters, which is what the
+    // console's line editor expects for Ctrl-U and Ctrl-W.
+    static ref DECODER: Mutex<Decoder> =
+        Mutex::new(Decoder::new(Layout::Us104, ScancodeSet::Set1, HandleControl::MapLettersToUnicode));
+}
+
+/// The keyboard layouts `pc-keyboard` knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    Dvorak104,
+    Azerty,
+}
+
+impl Layout {
+    /// The layout called `name` in a `keymap=` option: `us`, `uk`, `dvorak`
+    /// or `azerty`.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us104),
+            "uk" => Some(Layout::Uk105),
+            "dvorak" => Some(Layout::Dvorak104),
+            "azerty" => Some(Layout::Azerty),
+            _ => None,
+        }
+    }
+}
+
+/// Which scancodes the keyboard sends. The keyboard controller translates
+/// everything to set 1 unless `set_layout` asks for set 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+// `pc_keyboard::Keyboard` takes the layout and the scancode set as type
+// parameters, so switching at runtime means one variant per combination.
+macro_rules! decoders {
+    ($(($layout:ident, $set:ident) => $variant:ident($keyboard_layout:ident, $scancode_set:ident),)*) => {
+        enum DecoderKind {
+            $($variant(Keyboard<layouts::$keyboard_layout, $scancode_set>),)*
+        }
+
+        impl DecoderKind {
+            fn new(layout: Layout, scancode_set: ScancodeSet, handle_ctrl: HandleControl) -> DecoderKind {
+                match (layout, scancode_set) {
+                    $((Layout::$layout, ScancodeSet::$set) => DecoderKind::$variant(
+                        Keyboard::new(layouts::$keyboard_layout, $scancode_set, handle_ctrl),
+                    ),)*
+                }
+            }
+
+            fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+                match self {
+                    $(DecoderKind::$variant(keyboard) => keyboard.add_byte(scancode).ok().flatten(),)*
+                }
+            }
+
+            fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+                match self {
+                    $(DecoderKind::$variant(keyboard) => keyboard.process_keyevent(event),)*
+                }
+            }
+        }
+    };
+}
+
+decoders! {
+    (Us104, Set1) => Us104Set1(Us104Key, ScancodeSet1),
+    (Us104, Set2) => Us104Set2(Us104Key, ScancodeSet2),
+    (Uk105, Set1) => Uk105Set1(Uk105Key, ScancodeSet1),
+    (Uk105, Set2) => Uk105Set2(Uk105Key, ScancodeSet2),
+    (Dvorak104, Set1) => Dvorak104Set1(Dvorak104Key, ScancodeSet1),
+    (Dvorak104, Set2) => Dvorak104Set2(Dvorak104Key, ScancodeSet2),
+    (Azerty, Set1) => AzertySet1(Azerty, ScancodeSet1),
+    (Azerty, Set2) => AzertySet2(Azerty, ScancodeSet2),
+}
+
+// What a scancode comes to, when it finishes something.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Input {
+    Key(DecodedKey),
+    /// Alt+F1 to Alt+F6.
+    SwitchTo(Console),
+}
+
+/// Turns scancodes into keys, keeping track of the modifiers in between.
+struct Decoder {
+    kind: DecoderKind,
+    layout: Layout,
+    scancode_set: ScancodeSet,
+    handle_ctrl: HandleControl,
+    // `pc_keyboard` keeps its modifiers to itself, so these are tracked
+    // again here for the key combinations we handle ourselves.
+    left_shift: bool,
+    right_shift: bool,
+    alt: bool, // either of them
+}
+
+impl Decoder {
+    fn new(layout: Layout, scancode_set: ScancodeSet, handle_ctrl: HandleControl) -> Decoder {
+        Decoder {
+            kind: DecoderKind::new(layout, scancode_set, handle_ctrl),
+            layout,
+            scancode_set,
+            handle_ctrl,
+            left_shift: false,
+            right_shift: false,
+            alt: false,
+        }
+    }
+
+    fn add_byte(&mut self, scancode: u8) -> Option<Input> {
+        let event = self.kind.add_byte(scancode)?;
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            // Alt only matters to us, whoever reads keys doesn't see it.
+            // `pc_keyboard` still gets it, as AltGr picks characters.
+            KeyCode::AltLeft | KeyCode::AltRight => {
+                self.alt = down;
+                self.kind.process_keyevent(event);
+                return None;
+            }
+            // Shift+PageUp and Shift+PageDown already paged through the
+            // scrollback in `add_scancode`, whoever is reading keys never
+            // sees them.
+            KeyCode::PageUp | KeyCode::PageDown if down && self.shift() => return None,
+            // Nor Alt+F1 to Alt+F6, which switch virtual consoles.
+            code if down && self.alt => {
+                if let Some(console) = console_key(code).and_then(Console::new) {
+                    return Some(Input::SwitchTo(console));
+                }
+            }
+            _ => {}
+        }
+        self.kind.process_keyevent(event).map(Input::Key)
+    }
+
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+}
+
+// Which console a function key switches to with Alt.
+fn console_key(code: KeyCode) -> Option<usize> {
+    match code {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        KeyCode::F5 => Some(4),
+        KeyCode::F6 => Some(5),
+        _ => None,
+    }
+}
+
+/// Which way Shift+PageUp and Shift+PageDown scroll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scroll {
+    Back,
+    Forward,
+}
+
+// Follows just enough of the scancodes to spot Shift+PageUp and
+// Shift+PageDown, in either scancode set, without taking a lock. Only the
+// keyboard interrupt feeds it, so plain loads and stores are enough.
+struct ScrollKeys {
+    left_shift: AtomicBool,
+    right_shift: AtomicBool,
+    extended: AtomicBool, // got 0xe0
+    release: AtomicBool, // got 0xf0, set 2 only
+}
+
+impl ScrollKeys {
+    const fn new() -> ScrollKeys {
+        ScrollKeys {
+            left_shift: AtomicBool::new(false),
+            right_shift: AtomicBool::new(false),
+            extended: AtomicBool::new(false),
+            release: AtomicBool::new(false),
+        }
+    }
+
+    fn add_byte(&self, scancode: u8, scancode_set: ScancodeSet) -> Option<Scroll> {
+        // Set 1 marks releases with the top bit, set 2 with a 0xf0 first.
+        let (code, down) = match (scancode_set, scancode) {
+            (_, 0xe0) => {
+                self.extended.store(true, Ordering::Relaxed);
+                return None;
+            }
+            (ScancodeSet::Set2, 0xf0) => {
+                self.release.store(true, Ordering::Relaxed);
+                return None;
+            }
+            (ScancodeSet::Set1, scancode) => (scancode & 0x7f, scancode & 0x80 == 0),
+            (ScancodeSet::Set2, scancode) => (scancode, !self.release.swap(false, Ordering::Relaxed)),
+        };
+        let extended = self.extended.swap(false, Ordering::Relaxed);
+        let shift = self.left_shift.load(Ordering::Relaxed) || self.right_shift.load(Ordering::Relaxed);
+        // Extended shifts are ones the keyboard makes up around other
+        // keys, not the real thing.
+        match (scancode_set, extended, code) {
+            (ScancodeSet::Set1, false, 0x2a) | (ScancodeSet::Set2, false, 0x12) => {
+                self.left_shift.store(down, Ordering::Relaxed)
+            }
+            (ScancodeSet::Set1, false, 0x36) | (ScancodeSet::Set2, false, 0x59) => {
+                self.right_shift.store(down, Ordering::Relaxed)
+            }
+            // PageUp and PageDown.
+            (ScancodeSet::Set1, true, 0x49) | (ScancodeSet::Set2, true, 0x7d) if down && shift => {
+                return Some(Scroll::Back)
+            }
+            (ScancodeSet::Set1, true, 0x51) | (ScancodeSet::Set2, true, 0x7a) if down && shift => {
+                return Some(Scroll::Forward)
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Applies the `keymap=` command line option, if given. It names the
+/// layout, `us`, `uk`, `dvorak` or `azerty`, and can go on to pick the
+/// scancode set with `set1` or `set2`, and with `ctrl` or `noctrl` whether
+/// Ctrl+letter comes out as a control character, like `keymap=uk,set2,noctrl`.
+pub fn init() {
+    let value = match crate::cmdline::get("keymap") {
+        Some(value) => value,
+        None => return,
+    };
+    let (layout, scancode_set, handle_ctrl) = match parse_keymap(value, layout_settings()) {
+        Ok(settings) => settings,
+        Err(part) => {
+            crate::println!("keymap={}: unknown setting `{}`, using US", value, part);
+            return;
+        }
+    };
+    if let Err(error) = set_layout(layout, scancode_set, handle_ctrl) {
+        let (_, current_set, _) = layout_settings();
+        crate::println!("keymap={}: {:?} switching scancode sets, staying with {:?}", value, error, current_set);
+        // Can't fail, the controller is left as it is.
+        let _ = set_layout(layout, current_set, handle_ctrl);
+    }
+}
+
+// Reads a `keymap=` value, starting from `settings` for what it leaves out.
+// Gives back the part it doesn't understand.
+fn parse_keymap(
+    value: &str,
+    settings: (Layout, ScancodeSet, HandleControl),
+) -> Result<(Layout, ScancodeSet, HandleControl), &str> {
+    let (_, mut scancode_set, mut handle_ctrl) = settings;
+    let mut parts = value.split(',');
+    let name = parts.next().unwrap_or("");
+    let layout = Layout::from_name(name).ok_or(name)?;
+    for part in parts {
+        match part {
+            "set1" => scancode_set = ScancodeSet::Set1,
+            "set2" => scancode_set = ScancodeSet::Set2,
+            "ctrl" => handle_ctrl = HandleControl::MapLettersToUnicode,
+            "noctrl" => handle_ctrl = HandleControl::Ignore,
+            _ => return Err(part),
+        }
+    }
+    Ok((layout, scancode_set, handle_ctrl))
+}
+
+/// Decodes keys with `layout` from now on, for a keyboard sending
+/// `scancode_set`. With `HandleControl::MapLettersToUnicode`, Ctrl+A to
+/// Ctrl+Z come out as the control characters U+0001 to U+001A.
+///
+/// A different scancode set than before is set up on the keyboard
+/// controller too, see `i8042::set_scancode_set`. If that fails nothing
+/// changes.
+///
+/// Modifiers held right now are forgotten, and so is the first half of a
+/// multi-byte scancode.
+pub fn set_layout(layout: Layout, scancode_set: ScancodeSet, handle_ctrl: HandleControl) -> Result<(), I8042Error> {
+    let mut decoder = DECODER.lock();
+    if scancode_set != decoder.scancode_set {
+        crate::i8042::set_scancode_set(scancode_set)?;
+    }
+    *decoder = Decoder::new(layout, scancode_set, handle_ctrl);
+    SCANCODE_SET.store(scancode_set as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// What `set_layout` was last called with.
+pub fn layout_settings() -> (Layout, ScancodeSet, HandleControl) {
+    let decoder = DECODER.lock();
+    (decoder.layout, decoder.scancode_set, decoder.handle_ctrl)
+}
+
+/// Called by the keyboard interrupt handler with each byte it reads.
+pub(crate) fn add_scancode(scancode: u8) {
+    let scancode_set = match SCANCODE_SET.load(Ordering::Relaxed) {
+        set if set == ScancodeSet::Set2 as u8 => ScancodeSet::Set2,
+        _ => ScancodeSet::Set1,
+    };
+    match SCROLL_KEYS.add_byte(scancode, scancode_set) {
+        Some(Scroll::Back) => crate::vga_buffer::page_up(),
+        Some(Scroll::Forward) => crate::vga_buffer::page_down(),
+        None => {}
+    }
+    if SCANCODES.push(scancode).is_err() {
+        // Nobody is reading keys, or not fast enough.
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        WAKER.wake();
+    }
+}
+
+/// How many scancodes were dropped because the queue was full.
+pub fn dropped_scancodes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The next key pressed, if one is waiting. Scancodes that don't make a key
+/// on their own, like releases and modifiers, are used up along the way,
+/// and so are Alt+F1 to Alt+F6, which switch consoles.
+pub fn try_read_key() -> Option<DecodedKey> {
+    let mut decoder = DECODER.lock();
+    while let Some(scancode) = SCANCODES.pop() {
+        match decoder.add_byte(scancode) {
+            Some(Input::Key(key)) => return Some(key),
+            Some(Input::SwitchTo(console)) => console.show(),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Waits for the next key press, halting the CPU in between interrupts.
+pub fn read_key() -> DecodedKey {
+    loop {
+        if let Some(key) = try_read_key() {
+            return key;
+        }
+        // A scancode arriving between the check and the `hlt` would leave us
+        // asleep with a key waiting, so check again with interrupts off and
+        // let `sti; hlt` enable them and halt in one go.
+        interrupts::disable();
+        if SCANCODES.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+// Gets the next item with `read`, or registers the task to be woken up by
+// the keyboard interrupt. Registering comes before the second try, so a
+// scancode queued in between can't be missed.
+fn poll_with<T>(context: &mut Context, mut read: impl FnMut() -> Option<T>) -> Poll<Option<T>> {
+    if let Some(item) = read() {
+        return Poll::Ready(Some(item));
+    }
+    WAKER.register(context.waker());
+    match read() {
+        Some(item) => {
+            WAKER.take();
+            Poll::Ready(Some(item))
+        }
+        None => Poll::Pending,
+    }
+}
+
+/// The raw scancodes from the keyboard, as a stream that never ends.
+///
+/// Takes from the same queue as `read_key` and `KeyStream`, and only one task
+/// is woken up per scancode, so there should only be one reader at a time.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> ScancodeStream {
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        poll_with(context, || SCANCODES.pop())
+    }
+}
+
+/// Key presses, decoded like `read_key` does, as a stream that never ends,
+/// so a task can loop over them with `while let Some(key) = keys.next().await`.
+pub struct KeyStream {
+    _private: (),
+}
+
+impl KeyStream {
+    pub fn new() -> KeyStream {
+        KeyStream { _private: () }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<DecodedKey>> {
+        poll_with(context, try_read_key)
+    }
+}
+
+#[test_case]
+fn test_decodes_queued_scancodes() {
+    interrupts::without_interrupts(|| {
+        // Press and release `a`, then press Enter.
+        for &scancode in [0x1e, 0x9e, 0x1c].iter() {
+            add_scancode(scancode);
+        }
+        assert_eq!(try_read_key(), Some(DecodedKey::Unicode('a')));
+        assert_eq!(try_read_key(), Some(DecodedKey::Unicode('\n')));
+        assert_eq!(try_read_key(), None);
+    });
+}
+
+#[test_case]
+fn test_streams_yield_queued_input() {
+    use futures_util::stream::StreamExt;
+
+    interrupts::without_interrupts(|| {
+        add_scancode(0x1e); // `a` pressed
+        add_scancode(0x9e); // and released
+        add_scancode(0x30); // `b` pressed
+    });
+    crate::task::block_on(async {
+        assert_eq!(ScancodeStream::new().next().await, Some(0x1e));
+        // The release alone doesn't make a key, the stream moves on to `b`.
+        assert_eq!(KeyStream::new().next().await, Some(DecodedKey::Unicode('b')));
+    });
+}
+
+// Everything `scancodes` come to, consoles switched to included, without
+// switching any.
+#[cfg(test)]
+fn decode_inputs(layout: Layout, scancode_set: ScancodeSet, handle_ctrl: HandleControl, scancodes: &[u8]) -> alloc::vec::Vec<Input> {
+    let mut decoder = Decoder::new(layout, scancode_set, handle_ctrl);
+    scancodes.iter().filter_map(|&scancode| decoder.add_byte(scancode)).collect()
+}
+
+#[cfg(test)]
+fn decode_all(layout: Layout, scancode_set: ScancodeSet, handle_ctrl: HandleControl, scancodes: &[u8]) -> alloc::vec::Vec<DecodedKey> {
+    decode_inputs(layout, scancode_set, handle_ctrl, scancodes)
+        .into_iter()
+        .filter_map(|input| match input {
+            Input::Key(key) => Some(key),
+            Input::SwitchTo(_) => None,
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_layouts_map_the_same_key_differently() {
+    // The key right of Tab, then the one US keyboards have `\` on.
+    let scancodes = [0x10, 0x2b];
+    let decode = |layout| decode_all(layout, ScancodeSet::Set1, HandleControl::Ignore, &scancodes);
+    assert_eq!(decode(Layout::Us104), [DecodedKey::Unicode('q'), DecodedKey::Unicode('\\')]);
+    assert_eq!(decode(Layout::Uk105), [DecodedKey::Unicode('q'), DecodedKey::Unicode('#')]);
+    assert_eq!(decode(Layout::Dvorak104)[0], DecodedKey::Unicode('\''));
+    assert_eq!(decode(Layout::Azerty)[0], DecodedKey::Unicode('a'));
+}
+
+#[test_case]
+fn test_scancode_set_2() {
+    // `a` pressed and released, then Enter pressed.
+    let keys = decode_all(Layout::Us104, ScancodeSet::Set2, HandleControl::Ignore, &[0x1c, 0xf0, 0x1c, 0x5a]);
+    assert_eq!(keys, [DecodedKey::Unicode('a'), DecodedKey::Unicode('\n')]);
+}
+
+#[test_case]
+fn test_shift_page_up_is_kept_for_scrollback() {
+    // PageUp on its own, then with Left Shift held, then on its own again.
+    let scancodes = [0xe0, 0x49, 0xe0, 0xc9, 0x2a, 0xe0, 0x49, 0xe0, 0xc9, 0xaa, 0xe0, 0x49];
+    let keys = decode_all(Layout::Us104, ScancodeSet::Set1, HandleControl::Ignore, &scancodes);
+    assert_eq!(keys, [DecodedKey::RawKey(KeyCode::PageUp), DecodedKey::RawKey(KeyCode::PageUp)]);
+}
+
+#[test_case]
+fn test_scroll_keys_without_a_reader() {
+    let scroll = |scancode_set, scancodes: &[u8]| {
+        let keys = ScrollKeys::new();
+        scancodes.iter().filter_map(|&scancode| keys.add_byte(scancode, scancode_set)).collect::<alloc::vec::Vec<_>>()
+    };
+    // Set 1: PageUp alone, Right Shift + PageUp, Right Shift + PageDown,
+    // then PageDown after Shift is let go.
+    assert_eq!(
+        scroll(ScancodeSet::Set1, &[0xe0, 0x49, 0x36, 0xe0, 0x49, 0xe0, 0x51, 0xb6, 0xe0, 0x51]),
+        [Scroll::Back, Scroll::Forward]
+    );
+    // Set 2: the same with Left Shift, whose release is 0xf0 0x12.
+    assert_eq!(
+        scroll(ScancodeSet::Set2, &[0xe0, 0x7d, 0x12, 0xe0, 0x7d, 0xe0, 0x7a, 0xf0, 0x12, 0xe0, 0x7a]),
+        [Scroll::Back, Scroll::Forward]
+    );
+    // A made-up shift around an extended key doesn't count.
+    assert!(scroll(ScancodeSet::Set1, &[0xe0, 0x2a, 0xe0, 0x49]).is_empty());
+}
+
+#[test_case]
+fn test_alt_function_keys_switch_consoles() {
+    // Left Alt held while pressing and releasing F2 and then F1, then F1
+    // again once Alt is let go.
+    let scancodes = [0x38, 0x3c, 0xbc, 0x3b, 0xbb, 0xb8, 0x3b];
+    let inputs = decode_inputs(Layout::Us104, ScancodeSet::Set1, HandleControl::Ignore, &scancodes);
+    assert_eq!(
+        inputs,
+        [
+            Input::SwitchTo(Console::new(1).unwrap()),
+            Input::SwitchTo(Console::log()),
+            Input::Key(DecodedKey::RawKey(KeyCode::F1)),
+        ]
+    );
+    assert_eq!(console_key(KeyCode::F6), Some(5));
+    assert_eq!(console_key(KeyCode::F7), None);
+}
+
+#[test_case]
+fn test_ctrl_mapping() {
+    // Left Ctrl held down while pressing `c`.
+    let scancodes = [0x1d, 0x2e];
+    let mapped = decode_all(Layout::Us104, ScancodeSet::Set1, HandleControl::MapLettersToUnicode, &scancodes);
+    assert_eq!(mapped, [DecodedKey::Unicode('\u{3}')]);
+    let ignored = decode_all(Layout::Us104, ScancodeSet::Set1, HandleControl::Ignore, &scancodes);
+    assert_eq!(ignored, [DecodedKey::Unicode('c')]);
+}
+
+#[test_case]
+fn test_set_layout_applies_to_read_keys() {
+    let previous = layout_settings();
+    assert_eq!(set_layout(Layout::Azerty, ScancodeSet::Set1, HandleControl::Ignore), Ok(()));
+    assert_eq!(layout_settings(), (Layout::Azerty, ScancodeSet::Set1, HandleControl::Ignore));
+    interrupts::without_interrupts(|| {
+        add_scancode(0x10);
+        assert_eq!(try_read_key(), Some(DecodedKey::Unicode('a')));
+    });
+    assert_eq!(set_layout(previous.0, previous.1, previous.2), Ok(()));
+}
+
+#[test_case]
+fn test_set_layout_switches_the_scancode_set() {
+    let previous = layout_settings();
+    assert_eq!(set_layout(Layout::Us104, ScancodeSet::Set2, HandleControl::Ignore), Ok(()));
+    assert_eq!(layout_settings().1, ScancodeSet::Set2);
+    interrupts::without_interrupts(|| {
+        add_scancode(0x1c); // `a` in set 2, Enter in set 1
+        assert_eq!(try_read_key(), Some(DecodedKey::Unicode('a')));
+    });
+    assert_eq!(set_layout(previous.0, previous.1, previous.2), Ok(()));
+    assert_eq!(layout_settings(), previous);
+}
+
+#[test_case]
+fn test_keymap_option() {
+    let defaults = (Layout::Us104, ScancodeSet::Set1, HandleControl::MapLettersToUnicode);
+    assert_eq!(parse_keymap("uk", defaults), Ok((Layout::Uk105, ScancodeSet::Set1, HandleControl::MapLettersToUnicode)));
+    assert_eq!(
+        parse_keymap("dvorak,set2,noctrl", defaults),
+        Ok((Layout::Dvorak104, ScancodeSet::Set2, HandleControl::Ignore))
+    );
+    assert_eq!(parse_keymap("us,set1,ctrl", defaults), Ok(defaults));
+    assert_eq!(parse_keymap("colemak", defaults), Err("colemak"));
+    assert_eq!(parse_keymap("us,set3", defaults), Err("set3"));
+}
+
+#[test_case]
+fn test_layout_names() {
+    assert_eq!(Layout::from_name("uk"), Some(Layout::Uk105));
+    assert_eq!(Layout::from_name("dvorak"), Some(Layout::Dvorak104));
+    assert_eq!(Layout::from_name("colemak"), None);
+}
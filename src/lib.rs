@@ -6,11 +6,31 @@
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
+#![feature(asm)] // inline assembly, for the instructions x86_64 has no wrapper for
+#![feature(alloc_error_handler)] // lets us define what happens when the heap runs out
+#![feature(wake_trait)] // `Wake`, for building wakers out of an `Arc`
+extern crate alloc; // `Box`, `Vec` and friends, usable once the heap is set up
 use core::panic::PanicInfo;
+use bootloader::BootInfo;
+#[cfg(test)]
+use bootloader::entry_point;
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
+pub mod exceptions;
 pub mod gdt;
+pub mod memory;
+pub mod allocator;
+pub mod acpi;
+pub mod cmdline;
+pub mod apic;
+pub mod time;
+pub mod queue;
+pub mod i8042;
+pub mod keyboard;
+pub mod mouse;
+pub mod task;
+pub mod console;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -32,10 +52,23 @@ pub fn hlt_loop() -> ! {
         x86_64::instructions::hlt();
     }
 }
-pub fn init() {
+pub fn init(boot_info: &'static BootInfo) {
+    memory::init(boot_info); // records the memory map and where physical memory is mapped.
+    allocator::init_heap().expect("heap initialization failed");
+    vga_buffer::init(); // keeps what scrolls off the screen, now there's a heap for it.
+    acpi::init(); // finds the firmware's ACPI tables, if there are any.
     gdt::init(); // Initializes our gdt 
     interrupts::init_idt(); // initializes our idt for gdt
-    unsafe { interrupts::PICS.lock().initialize()};
+    interrupts::init_irqs(); // the APIC if we have one, the PICs if not.
+    serial::init(); // takes input from the serial port as well.
+    if let Err(error) = i8042::init() { // resets the PS/2 controller, `i8042::info()` says what it found.
+        println!("PS/2 controller: {:?}, carrying on without it", error);
+    }
+    if let Err(error) = mouse::init() { // the mouse on the PS/2 controller, if there is one.
+        println!("PS/2 mouse: {:?}, carrying on without it", error);
+    }
+    time::init(); // starts the timer ticking.
+    keyboard::init(); // picks the keyboard layout.
     x86_64::instructions::interrupts::enable(); // executes set interrupts instruction.
 }
 pub trait Testable {
@@ -48,8 +81,10 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
+        let stopwatch = time::Stopwatch::start();
         self();
-        serial_println!("[ok]");
+        let micros = stopwatch.elapsed().as_micros();
+        serial_println!("[ok] {}.{:03} ms", micros / 1000, micros % 1000);
     }
 }
 
@@ -68,11 +103,123 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// What one of the exception tests in `tests/` expects the kernel's handler
+/// to have reported.
+pub struct ExpectedException {
+    pub exception: exceptions::Exception,
+    /// The report's first line.
+    pub header: &'static str,
+    /// Where the faulting instruction is, usually `code_of(trigger)`.
+    pub instruction_pointer: core::ops::Range<u64>,
+    /// The bytes of the faulting instruction, if the test wants them checked.
+    pub instruction: Option<&'static [u8]>,
+    pub error_code: Option<u64>,
+    /// Whether the decoded detail is the right one.
+    pub detail: fn(exceptions::ExceptionDetail) -> bool,
+    /// Text the report has to have besides the header, instruction pointer
+    /// and error code, which are checked anyway.
+    pub report_contains: &'static [&'static str],
+}
+
+/// The start of `function`, which is where a test's faulting instruction
+/// should be if `function` is small and never inlined.
+pub fn code_of(function: fn()) -> core::ops::Range<u64> {
+    let start = function as usize as u64;
+    start..start + 0x100
+}
+
+/// The panic handler for the exception tests. The kernel's handler reports
+/// the exception and then panics, which brings us here to check the report
+/// against `expected`.
+pub fn exception_test_panic_handler(info: &PanicInfo, expected: &ExpectedException) -> ! {
+    use alloc::format;
+
+    let report = match exceptions::last_exception() {
+        Some(report) => report,
+        None => test_panic_handler(info), // panicked for some other reason
+    };
+    let rip = report.instruction_pointer.as_u64();
+    check_or_fail(
+        report.exception == expected.exception,
+        format_args!("reported {:?}", report.exception),
+    );
+    check_or_fail(
+        expected.instruction_pointer.contains(&rip),
+        format_args!("instruction pointer {:#x} is not in {:#x?}", rip, expected.instruction_pointer),
+    );
+    if let Some(instruction) = expected.instruction {
+        let found = unsafe { core::slice::from_raw_parts(rip as *const u8, instruction.len()) };
+        check_or_fail(
+            found == instruction,
+            format_args!("instruction pointer is at {:x?}", found),
+        );
+    }
+    check_or_fail(
+        report.error_code == expected.error_code,
+        format_args!("error code {:#x?}", report.error_code),
+    );
+    check_or_fail(
+        (expected.detail)(report.detail),
+        format_args!("wrong detail {:?}", report.detail),
+    );
+
+    let text = format!("{}", report);
+    let mut lines = text.lines();
+    check_or_fail(lines.next() == Some(expected.header), format_args!("wrong header in\n{}", text));
+    check_or_fail(
+        text.contains(&format!("  instruction pointer: {:#x}\n", rip)),
+        format_args!("instruction pointer not reported in\n{}", text),
+    );
+    match expected.error_code {
+        // The page fault flags follow the number on the same line.
+        Some(error_code) => {
+            let reported = format!("  error code:          {:#x}", error_code);
+            check_or_fail(
+                lines.any(|line| line == reported || line.starts_with(&format!("{} ", reported))),
+                format_args!("error code not reported in\n{}", text),
+            );
+        }
+        None => check_or_fail(
+            !text.contains("error code"),
+            format_args!("reported an error code in\n{}", text),
+        ),
+    }
+    for expected_text in expected.report_contains {
+        check_or_fail(
+            text.contains(expected_text),
+            format_args!("{:?} not reported in\n{}", expected_text, text),
+        );
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
+
+/// For the end of an exception test's `main`, which it only gets to if
+/// the exception never happened.
+pub fn exception_test_did_not_fault() -> ! {
+    serial_println!("[test did not fault]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+fn check_or_fail(condition: bool, message: core::fmt::Arguments) {
+    if !condition {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", message);
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop();
+    }
+}
+
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     hlt_loop()
 }
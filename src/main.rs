@@ -4,17 +4,26 @@
 #![test_runner(kurogane_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 use core::panic::PanicInfo;
-mod vga_buffer;
-mod serial;
+use bootloader::{BootInfo, entry_point};
+use core::fmt::Write;
+use kurogane_os::console;
+use kurogane_os::vga_buffer::Console;
+use kurogane_os::task::{executor::Executor, Task};
+use kurogane_os::{println, serial_print, serial_println};
 
-// We use no mange to disable name mangling, so that the output actually,
-// has the name _start, as opposed to K#JH$K28294.sd3.core$_fn.2392032
-#[no_mangle] 
-pub extern "C" fn _start() -> ! { // returns `Never` type for diverging function.
+// entry_point! defines the real `_start` for us (name mangling disabled and all),
+// and type checks that our function takes the `BootInfo` the bootloader passes,
+// which a bare `extern "C" fn _start` can't do.
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! { // returns `Never` type for diverging function.
 
     println!("Kurogane!");
     
-    kurogane_os::init();
+    kurogane_os::init(boot_info);
+    kurogane_os::memory::print_memory_map();
+    println!("interrupt controller: {:?}", kurogane_os::interrupts::interrupt_controller());
+    println!("PS/2 controller: {:?}", kurogane_os::i8042::info());
 
 
     
@@ -22,7 +31,8 @@ pub extern "C" fn _start() -> ! { // returns `Never` type for diverging function
     test_main();
     
     println!("In the meantime, save yourself. Everything else? Get a thumb drive.");
-    println!("Enter, Kurogane")
+    println!("Enter, Kurogane");
+    println!("Alt+F2 for a prompt, Alt+F1 back to this log.");
     // Extern "C" tells the compiler that it should use the C calling convention
     // Casts the hexadecimal integer to a raw pointer
     // raw pointers can ignore borrowing rules, having both mutable and 
@@ -32,7 +42,30 @@ pub extern "C" fn _start() -> ! { // returns `Never` type for diverging function
     // Don't have automatic cleanup
     // We use enumerate to get a running variable, and we use offset method
     // to write the string and the corresponding color byte.
-    kurogane_os::hlt_loop();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(echo_lines()));
+    executor.spawn(Task::new(echo_serial_lines()));
+    executor.run();
+}
+
+// Reads lines typed on the keyboard and echoes them back, on the second
+// virtual console so the kernel log doesn't get in the way.
+async fn echo_lines() {
+    let mut shell = Console::new(1).unwrap();
+    loop {
+        write!(shell, "> ").unwrap();
+        let line = console::read_line_on(shell).await;
+        writeln!(shell, "{}", line).unwrap();
+    }
+}
+
+// The same for a terminal on the serial port.
+async fn echo_serial_lines() {
+    loop {
+        serial_print!("> ");
+        let line = console::read_serial_line().await;
+        serial_println!("{}", line);
+    }
 }
 
 
@@ -60,6 +93,9 @@ fn panic(info: &PanicInfo) -> ! {
     // We re-implement panic as it comes from the stdlib,
     // which we disabled earlier.\
     // Panic Info contains the file and line that caused the panic
+    // It's printed to the log, so put that on the screen in case another
+    // console is shown.
+    Console::log().try_show();
     println!("{}", info);
     // This function should never return,
     // so we mark it as a diverging function, with the "never" type `!`.
@@ -0,0 +1,220 @@
+use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegion, MemoryRegionType};
+use core::fmt;
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod frame_allocator;
+pub mod paging;
+
+pub use self::frame_allocator::BitmapFrameAllocator;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// Both of these are handed to us once by the bootloader and never change
+// afterwards, so a `Once` is enough, no lock is needed to read them.
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+static FRAME_ALLOCATOR: Once<Mutex<BitmapFrameAllocator>> = Once::new();
+
+/// What a region of physical memory is being used for, collapsed from the
+/// finer grained `MemoryRegionType` the bootloader reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Free for the kernel to hand out.
+    Usable,
+    /// The kernel image and its stack.
+    Kernel,
+    /// Memory the bootloader used for itself, the page tables and the boot info.
+    Bootloader,
+    /// ACPI tables, which can be reclaimed once they have been parsed.
+    AcpiReclaimable,
+    /// ACPI non-volatile storage, which must never be touched.
+    AcpiNvs,
+    /// Reserved by the firmware or the hardware.
+    Reserved,
+    /// Memory the firmware flagged as faulty.
+    BadMemory,
+}
+
+impl From<MemoryRegionType> for RegionKind {
+    fn from(region_type: MemoryRegionType) -> RegionKind {
+        match region_type {
+            MemoryRegionType::Usable => RegionKind::Usable,
+            MemoryRegionType::Kernel | MemoryRegionType::KernelStack => RegionKind::Kernel,
+            MemoryRegionType::InUse
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package
+            | MemoryRegionType::FrameZero => RegionKind::Bootloader,
+            MemoryRegionType::AcpiReclaimable => RegionKind::AcpiReclaimable,
+            MemoryRegionType::AcpiNvs => RegionKind::AcpiNvs,
+            MemoryRegionType::BadMemory => RegionKind::BadMemory,
+            _ => RegionKind::Reserved, // `Empty` and anything added to the bootloader later.
+        }
+    }
+}
+
+/// A contiguous range of physical memory, `start` inclusive and `end` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysRegion {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub kind: RegionKind,
+}
+
+impl PhysRegion {
+    fn from_bootloader(region: &MemoryRegion) -> PhysRegion {
+        PhysRegion {
+            start: PhysAddr::new(region.range.start_addr()),
+            end: PhysAddr::new(region.range.end_addr()),
+            kind: RegionKind::from(region.region_type),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.kind == RegionKind::Usable
+    }
+
+    /// The 4 KiB frames covered by this region.
+    pub fn frames(&self) -> PhysFrameRange<Size4KiB> {
+        PhysFrame::range(
+            PhysFrame::containing_address(self.start),
+            PhysFrame::containing_address(self.end),
+        )
+    }
+}
+
+impl fmt::Display for PhysRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#012x}..{:#012x} {:>8} KiB {:?}",
+            self.start.as_u64(),
+            self.end.as_u64(),
+            self.size() / 1024,
+            self.kind
+        )
+    }
+}
+
+/// An iterator over the regions of the bootloader memory map, in ascending
+/// address order.
+#[derive(Clone)]
+pub struct Regions {
+    inner: core::slice::Iter<'static, MemoryRegion>,
+}
+
+impl Iterator for Regions {
+    type Item = PhysRegion;
+
+    fn next(&mut self) -> Option<PhysRegion> {
+        self.inner.next().map(PhysRegion::from_bootloader)
+    }
+}
+
+pub fn init(boot_info: &'static BootInfo) {
+    MEMORY_MAP.call_once(|| &boot_info.memory_map);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| VirtAddr::new(boot_info.physical_memory_offset));
+    FRAME_ALLOCATOR.call_once(|| {
+        // The bootloader only reports frames nothing else is using as usable,
+        // and we just recorded where it mapped physical memory.
+        Mutex::new(unsafe { BitmapFrameAllocator::init(usable_regions()) })
+    });
+    paging::init();
+}
+
+/// The kernel's physical frame allocator.
+pub fn frame_allocator() -> MutexGuard<'static, BitmapFrameAllocator> {
+    FRAME_ALLOCATOR
+        .wait()
+        .expect("memory::init has not been called")
+        .lock()
+}
+
+fn memory_map() -> &'static MemoryMap {
+    MEMORY_MAP.wait().expect("memory::init has not been called")
+}
+
+/// Every region the bootloader reported.
+pub fn regions() -> Regions {
+    Regions { inner: memory_map().iter() }
+}
+
+pub fn regions_of(kind: RegionKind) -> impl Iterator<Item = PhysRegion> + Clone {
+    regions().filter(move |region| region.kind == kind)
+}
+
+pub fn usable_regions() -> impl Iterator<Item = PhysRegion> + Clone {
+    regions_of(RegionKind::Usable)
+}
+
+pub fn total_bytes(kind: RegionKind) -> u64 {
+    regions_of(kind).map(|region| region.size()).sum()
+}
+
+/// The end of the highest region of the memory map, this is also where the
+/// bootloader stops mapping physical memory.
+pub fn max_physical_address() -> PhysAddr {
+    regions()
+        .map(|region| region.end)
+        .max()
+        .unwrap_or_else(|| PhysAddr::new(0))
+}
+
+/// Where the bootloader mapped the whole of physical memory in our address space.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .wait()
+        .expect("memory::init has not been called")
+}
+
+/// Translates a physical address into the virtual address it can be reached
+/// at through the bootloader's physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+pub fn print_memory_map() {
+    use crate::println;
+    for region in regions() {
+        println!("{}", region);
+    }
+    println!(
+        "usable: {} KiB, reserved: {} KiB",
+        total_bytes(RegionKind::Usable) / 1024,
+        regions()
+            .filter(|region| !region.is_usable())
+            .map(|region| region.size())
+            .sum::<u64>()
+            / 1024
+    );
+}
+
+#[test_case]
+fn test_memory_map_has_usable_memory() {
+    assert!(usable_regions().count() > 0);
+    assert!(total_bytes(RegionKind::Usable) > 0);
+}
+
+#[test_case]
+fn test_regions_are_sorted_and_disjoint() {
+    let mut previous_end = PhysAddr::new(0);
+    for region in regions() {
+        assert!(region.start >= previous_end);
+        assert!(region.end > region.start);
+        previous_end = region.end;
+    }
+}
+
+#[test_case]
+fn test_kernel_region_is_reported() {
+    assert!(total_bytes(RegionKind::Kernel) > 0);
+    assert!(total_bytes(RegionKind::Bootloader) > 0);
+}
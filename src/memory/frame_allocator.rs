@@ -0,0 +1,258 @@
+use super::{phys_to_virt, PhysRegion, PAGE_SIZE};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+const BITS_PER_WORD: usize = 64;
+
+/// A physical frame allocator that keeps one bit per 4 KiB frame, set when
+/// the frame is in use.
+///
+/// The bitmap covers every frame from address zero up to the end of the
+/// highest usable region, so a frame's index is simply its frame number.
+/// Everything the bootloader did not report as usable starts out marked as
+/// used and is never handed out.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,  // number of frames the bitmap covers
+    total_frames: usize, // usable frames, not counting the ones holding the bitmap
+    free_frames: usize,
+    next: usize, // where the next search starts, so we don't rescan the low frames every time
+}
+
+impl BitmapFrameAllocator {
+    /// Builds an allocator from the usable regions of the memory map,
+    /// storing the bitmap itself in the first usable region big enough to
+    /// hold it.
+    ///
+    /// Unsafe because the caller must guarantee that the regions really are
+    /// unused, and that physical memory is mapped at `memory::physical_memory_offset()`.
+    pub unsafe fn init<I>(usable: I) -> BitmapFrameAllocator
+    where
+        I: Iterator<Item = PhysRegion> + Clone,
+    {
+        let frame_count = usable
+            .clone()
+            .map(|region| (region.end.as_u64() / PAGE_SIZE) as usize)
+            .max()
+            .unwrap_or(0);
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (words * 8) as u64;
+
+        let home = usable
+            .clone()
+            .find(|region| region.size() >= bitmap_bytes)
+            .expect("no usable region is large enough for the frame bitmap");
+        let bitmap_ptr = phys_to_virt(home.start).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+
+        let mut allocator = BitmapFrameAllocator::new(bitmap, frame_count, usable);
+        // The frames holding the bitmap can't be handed out, take them back out.
+        let bitmap_frames = PhysFrame::range(
+            PhysFrame::containing_address(home.start),
+            PhysFrame::containing_address(home.start + bitmap_bytes + (PAGE_SIZE - 1)),
+        );
+        for frame in bitmap_frames {
+            allocator.mark_used(frame_index(frame));
+        }
+        allocator.total_frames = allocator.free_frames;
+        allocator
+    }
+
+    /// Builds an allocator on top of caller provided bitmap storage, with
+    /// only the frames of `usable` marked free.
+    pub fn new<I>(bitmap: &'static mut [u64], frame_count: usize, usable: I) -> BitmapFrameAllocator
+    where
+        I: Iterator<Item = PhysRegion>,
+    {
+        assert!(bitmap.len() * BITS_PER_WORD >= frame_count, "frame bitmap is too small");
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+        for region in usable {
+            for frame in region.frames() {
+                let index = frame_index(frame);
+                if index < frame_count && allocator.is_used(index) {
+                    allocator.mark_free(index);
+                }
+            }
+        }
+        allocator.total_frames = allocator.free_frames;
+        allocator
+    }
+
+    /// Usable frames this allocator manages.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+        let index = frame_index(frame);
+        index >= self.frame_count || self.is_used(index)
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame
+    /// number is a multiple of `align` (in frames, a power of two), as DMA
+    /// capable devices usually need.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "frame alignment must be a power of two");
+        if count == 0 {
+            return None;
+        }
+
+        let mut start = 0;
+        loop {
+            start = (start + align - 1) & !(align - 1);
+            if start + count > self.frame_count {
+                return None;
+            }
+            // Jump past the last used frame in the candidate run, no run
+            // starting before it can fit either.
+            match (start..start + count).rev().find(|&index| self.is_used(index)) {
+                Some(used) => start = used + 1,
+                None => break,
+            }
+        }
+
+        for index in start..start + count {
+            self.mark_used(index);
+        }
+        Some(PhysFrame::range(frame_at(start), frame_at(start + count)))
+    }
+
+    /// Gives back a run of frames from `allocate_contiguous`.
+    ///
+    /// Unsafe because the caller must guarantee the frames are no longer in use.
+    pub unsafe fn deallocate_contiguous(&mut self, frames: PhysFrameRange) {
+        for frame in frames {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn find_free(&self, from: usize) -> Option<usize> {
+        let mut index = from;
+        while index < self.frame_count {
+            if self.bitmap[index / BITS_PER_WORD] == !0 {
+                // Skip whole words of used frames at once.
+                index = (index / BITS_PER_WORD + 1) * BITS_PER_WORD;
+                continue;
+            }
+            if !self.is_used(index) {
+                return Some(index);
+            }
+            index += 1;
+        }
+        None
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index));
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let index = self.find_free(self.next).or_else(|| self.find_free(0))?;
+        self.mark_used(index);
+        self.next = index + 1;
+        Some(frame_at(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(index < self.frame_count, "freed frame {:?} is outside of physical memory", frame);
+        assert!(self.is_used(index), "double free of frame {:?}", frame);
+        self.mark_free(index);
+        if index < self.next {
+            self.next = index;
+        }
+    }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / PAGE_SIZE) as usize
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * PAGE_SIZE))
+}
+
+#[test_case]
+fn test_allocate_and_deallocate() {
+    let mut allocator = super::frame_allocator();
+    let free_before = allocator.free_frames();
+    let first = allocator.allocate_frame().expect("out of frames");
+    let second = allocator.allocate_frame().expect("out of frames");
+    assert_ne!(first, second);
+    assert!(allocator.is_allocated(first));
+    assert_eq!(allocator.free_frames(), free_before - 2);
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+    assert!(!allocator.is_allocated(first));
+    assert_eq!(allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn test_allocated_frame_is_writable() {
+    let mut allocator = super::frame_allocator();
+    let frame = allocator.allocate_frame().expect("out of frames");
+    let ptr = phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+        allocator.deallocate_frame(frame);
+    }
+}
+
+#[test_case]
+fn test_contiguous_allocation() {
+    let mut allocator = super::frame_allocator();
+    let free_before = allocator.free_frames();
+    let frames = allocator.allocate_contiguous(16, 16).expect("no contiguous run of 16 frames");
+    assert_eq!(frames.end.start_address() - frames.start.start_address(), 16 * PAGE_SIZE);
+    assert_eq!(frame_index(frames.start) % 16, 0);
+    for frame in frames {
+        assert!(allocator.is_allocated(frame));
+    }
+    assert_eq!(allocator.free_frames(), free_before - 16);
+    unsafe { allocator.deallocate_contiguous(frames) };
+    assert_eq!(allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn test_frame_counters() {
+    let allocator = super::frame_allocator();
+    assert!(allocator.total_frames() > 0);
+    assert!(allocator.free_frames() <= allocator.total_frames());
+    assert_eq!(allocator.used_frames(), allocator.total_frames() - allocator.free_frames());
+}
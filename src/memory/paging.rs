@@ -0,0 +1,322 @@
+use super::{frame_allocator, physical_memory_offset, PAGE_SIZE};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, TranslateError, UnmapError};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageTable,
+    PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Device memory gets mapped into its own window, handed out front to back
+// and never reused, there are only ever a handful of devices.
+pub const MMIO_START: u64 = 0x_5000_0000_0000;
+pub const MMIO_SIZE: u64 = 0x_0100_0000_0000;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+static KERNEL_PAGE_TABLE: Once<Mutex<KernelPageTable>> = Once::new();
+
+/// Why a paging operation failed, merging the separate error types
+/// `x86_64` has for each `Mapper` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// No physical frame was left for the page or for an intermediate table.
+    FrameAllocationFailed,
+    /// The page already points at the given frame.
+    PageAlreadyMapped(PhysFrame),
+    PageNotMapped,
+    /// The address lies inside a 2 MiB or 1 GiB huge page, which can only be
+    /// changed as a whole.
+    ParentEntryHugePage,
+    InvalidFrameAddress(PhysAddr),
+    /// The MMIO window has no virtual address space left.
+    OutOfVirtualMemory,
+}
+
+impl From<MapToError<Size4KiB>> for PagingError {
+    fn from(error: MapToError<Size4KiB>) -> PagingError {
+        match error {
+            MapToError::FrameAllocationFailed => PagingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => PagingError::PageAlreadyMapped(frame),
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(error: UnmapError) -> PagingError {
+        match error {
+            UnmapError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            UnmapError::PageNotMapped => PagingError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(addr) => PagingError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(error: FlagUpdateError) -> PagingError {
+        match error {
+            FlagUpdateError::PageNotMapped => PagingError::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+        }
+    }
+}
+
+impl From<TranslateError> for PagingError {
+    fn from(error: TranslateError) -> PagingError {
+        match error {
+            TranslateError::PageNotMapped => PagingError::PageNotMapped,
+            TranslateError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            TranslateError::InvalidFrameAddress(addr) => PagingError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+/// The page tables the kernel is running on, which are the ones the
+/// bootloader built for us.
+///
+/// Every method that changes a mapping flushes the page from the TLB before
+/// returning, so callers never see a stale translation.
+pub struct KernelPageTable {
+    mapper: OffsetPageTable<'static>,
+}
+
+impl KernelPageTable {
+    /// Maps `page` to `frame`, taking any frames needed for new page tables
+    /// from the kernel frame allocator.
+    ///
+    /// Unsafe because mapping a frame that is already in use elsewhere
+    /// aliases that memory.
+    pub unsafe fn map(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        self.mapper
+            .map_to(page, frame, flags, &mut *frame_allocator())?
+            .flush();
+        Ok(())
+    }
+
+    /// Backs every page of `pages` with a freshly allocated frame.
+    ///
+    /// If a page fails to map, the pages mapped so far are unmapped again
+    /// and their frames freed.
+    pub fn map_range(&mut self, pages: PageRange, flags: PageTableFlags) -> Result<(), PagingError> {
+        for page in pages {
+            // Own statement, so the allocator lock is released before `map`
+            // needs it again for the page tables.
+            let frame = frame_allocator().allocate_frame();
+            let result = match frame {
+                // The frame came straight from the allocator, nothing else can be using it.
+                Some(frame) => unsafe { self.map(page, frame, flags) }.map_err(|error| {
+                    unsafe { frame_allocator().deallocate_frame(frame) };
+                    error
+                }),
+                None => Err(PagingError::FrameAllocationFailed),
+            };
+            if let Err(error) = result {
+                self.unmap_range(Page::range(pages.start, page));
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the mapping of `page` and returns the frame it pointed at,
+    /// which the caller now owns.
+    pub fn unmap(&mut self, page: Page) -> Result<PhysFrame, PagingError> {
+        let (frame, flush) = self.mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    }
+
+    /// Unmaps every page of `pages` that came from `map_range` and returns
+    /// the frames to the frame allocator, pages that are not mapped are skipped.
+    pub fn unmap_range(&mut self, pages: PageRange) {
+        for page in pages {
+            if let Ok(frame) = self.unmap(page) {
+                unsafe { frame_allocator().deallocate_frame(frame) };
+            }
+        }
+    }
+
+    /// Changes the flags of an existing mapping, without touching the frame.
+    ///
+    /// Unsafe because dropping `WRITABLE` or `PRESENT` from a page that is in
+    /// use makes its next access fault.
+    pub unsafe fn remap(&mut self, page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+        self.mapper.update_flags(page, flags)?.flush();
+        Ok(())
+    }
+
+    /// Finds the frame a 4 KiB page is mapped to.
+    pub fn translate_page(&self, page: Page) -> Result<PhysFrame, PagingError> {
+        Ok(self.mapper.translate_page(page)?)
+    }
+
+    /// Translates a virtual address to the physical address it is mapped to,
+    /// looking through huge pages as well.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+
+    pub fn is_mapped(&self, addr: VirtAddr) -> bool {
+        self.translate(addr).is_some()
+    }
+
+    /// Maps `size` bytes of device memory starting at `phys` into the MMIO
+    /// window, uncached, and returns the virtual address of `phys`.
+    ///
+    /// The bootloader maps physical memory with 2 MiB pages, which we can't
+    /// mark uncacheable one device at a time, hence the separate window.
+    ///
+    /// Unsafe because the caller must guarantee `phys` really is device memory.
+    pub unsafe fn map_mmio(&mut self, phys: PhysAddr, size: u64) -> Result<VirtAddr, PagingError> {
+        let first = PhysFrame::<Size4KiB>::containing_address(phys);
+        let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+        let bytes = last.start_address() - first.start_address() + PAGE_SIZE;
+
+        let start = reserve_mmio(bytes)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_EXECUTE;
+        let first_page = Page::containing_address(VirtAddr::new(start));
+        let mut page = first_page;
+        for frame in PhysFrame::range_inclusive(first, last) {
+            if let Err(error) = self.map(page, frame, flags) {
+                // Undo the pages mapped so far. The frames are the device's,
+                // not the frame allocator's, so they aren't freed.
+                for mapped in Page::range(first_page, page) {
+                    let _ = self.unmap(mapped);
+                }
+                release_mmio(start, bytes);
+                return Err(error);
+            }
+            page += 1;
+        }
+        Ok(VirtAddr::new(start) + (phys - first.start_address()))
+    }
+}
+
+// Takes `bytes` from the front of the MMIO window, only if they fit.
+fn reserve_mmio(bytes: u64) -> Result<u64, PagingError> {
+    let mut start = MMIO_NEXT.load(Ordering::SeqCst);
+    loop {
+        let end = start.checked_add(bytes).ok_or(PagingError::OutOfVirtualMemory)?;
+        if end > MMIO_START + MMIO_SIZE {
+            return Err(PagingError::OutOfVirtualMemory);
+        }
+        match MMIO_NEXT.compare_exchange(start, end, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return Ok(start),
+            Err(next) => start = next,
+        }
+    }
+}
+
+// Gives back what `reserve_mmio` took, if nothing was reserved after it.
+fn release_mmio(start: u64, bytes: u64) {
+    let _ = MMIO_NEXT.compare_exchange(start + bytes, start, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Takes over the active page tables, must run after `memory::init`.
+pub fn init() {
+    KERNEL_PAGE_TABLE.call_once(|| {
+        // The bootloader mapped all of physical memory at the offset, and we
+        // only ever do this once, so this is the only reference to the table.
+        let level_4_table = unsafe { active_level_4_table(physical_memory_offset()) };
+        Mutex::new(KernelPageTable {
+            mapper: unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset()) },
+        })
+    });
+}
+
+/// The kernel's page tables.
+pub fn kernel_page_table() -> MutexGuard<'static, KernelPageTable> {
+    KERNEL_PAGE_TABLE
+        .wait()
+        .expect("memory::paging::init has not been called")
+        .lock()
+}
+
+/// Shorthand for `kernel_page_table().translate(addr)`.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    kernel_page_table().translate(addr)
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    // CR3 holds the physical frame of the level 4 table, which we can reach
+    // through the physical memory mapping.
+    let (level_4_table_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+#[cfg(test)]
+const TEST_PAGE: u64 = 0x_7777_0000_0000;
+
+#[test_case]
+fn test_map_translate_unmap() {
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut table = kernel_page_table();
+    table.map_range(Page::range(page, page + 1), flags).expect("map failed");
+
+    let frame = table.translate_page(page).expect("page not mapped");
+    assert_eq!(table.translate(page.start_address() + 0x123u64), Some(frame.start_address() + 0x123u64));
+    unsafe {
+        let ptr = page.start_address().as_mut_ptr::<u64>();
+        ptr.write_volatile(0x_f00d);
+        assert_eq!(ptr.read_volatile(), 0x_f00d);
+    }
+
+    table.unmap_range(Page::range(page, page + 1));
+    assert!(!table.is_mapped(page.start_address()));
+    assert_eq!(table.unmap(page), Err(PagingError::PageNotMapped));
+}
+
+#[test_case]
+fn test_remap_changes_flags() {
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE + PAGE_SIZE));
+    let mut table = kernel_page_table();
+    table
+        .map_range(Page::range(page, page + 1), PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .expect("map failed");
+    let frame = table.translate_page(page).unwrap();
+    unsafe { table.remap(page, PageTableFlags::PRESENT) }.expect("remap failed");
+    // Remapping keeps the frame, only the flags change.
+    assert_eq!(table.translate_page(page), Ok(frame));
+    table.unmap_range(Page::range(page, page + 1));
+}
+
+#[test_case]
+fn test_map_range_frees_frames_on_unmap() {
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE + 16 * PAGE_SIZE));
+    let mut table = kernel_page_table();
+    table
+        .map_range(Page::range(page, page + 4), PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .expect("map failed");
+    let free = frame_allocator().free_frames();
+    table.unmap_range(Page::range(page, page + 4));
+    assert_eq!(frame_allocator().free_frames(), free + 4);
+}
+
+#[test_case]
+fn test_physical_memory_is_translated() {
+    // The bootloader's own mapping of physical memory uses huge pages.
+    let phys = PhysAddr::new(0xb8000);
+    assert_eq!(translate(physical_memory_offset() + phys.as_u64()), Some(phys));
+}
+
+#[test_case]
+fn test_map_mmio_too_big_keeps_window() {
+    let next = MMIO_NEXT.load(Ordering::SeqCst);
+    let result = unsafe { kernel_page_table().map_mmio(PhysAddr::new(0xfee0_0000), MMIO_SIZE + PAGE_SIZE) };
+    assert_eq!(result, Err(PagingError::OutOfVirtualMemory));
+    assert_eq!(MMIO_NEXT.load(Ordering::SeqCst), next);
+}
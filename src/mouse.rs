@@ -0,0 +1,376 @@
+use crate::i8042::{self, DeviceType, I8042Error, Ps2Port};
+use crate::interrupts;
+use crate::queue::IrqQueue;
+use crate::vga_buffer::Console;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+// A PS/2 mouse on the i8042's second port. It sends a packet of three bytes
+// every time it moves or a button changes, four once it's been switched to
+// IntelliMouse mode, which adds the wheel (and buttons 4 and 5).
+//
+// Unlike the keyboard the packets are decoded right in the interrupt
+// handler: they're short, need no lookup tables, and the mouse cursor has to
+// follow the mouse even when nobody is reading events.
+
+/// The line the mouse interrupts on, on the slave PIC.
+pub const MOUSE_IRQ: u8 = 12;
+
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+// Bits of a packet's first byte.
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+// Bits of the fourth byte on a five button mouse.
+const FOURTH_BUTTON: u8 = 1 << 4;
+const FIFTH_BUTTON: u8 = 1 << 5;
+
+// How far the mouse has to move for the cursor to move one cell. The mouse
+// counts in units of roughly 1/4 mm at its default resolution.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+const COLUMNS: i32 = 80;
+const ROWS: i32 = 25;
+
+static EVENTS: IrqQueue<MouseEvent> = IrqQueue::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static WAKER: AtomicWaker = AtomicWaker::new();
+static STATE: Once<Mutex<MouseState>> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Fourth,
+    Fifth,
+}
+
+const BUTTONS: [(MouseButton, u8); 5] = [
+    (MouseButton::Left, 1 << 0),
+    (MouseButton::Right, 1 << 1),
+    (MouseButton::Middle, 1 << 2),
+    (MouseButton::Fourth, 1 << 3),
+    (MouseButton::Fifth, 1 << 4),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Moved by `dx` and `dy` counts, with `dy` growing downwards like rows
+    /// on the screen do.
+    Move { dx: i16, dy: i16 },
+    Press(MouseButton),
+    Release(MouseButton),
+    /// Turned the wheel, by positive amounts when rolled towards the user.
+    Scroll(i8),
+}
+
+/// One packet, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    dx: i16,
+    dy: i16, // positive is up, like the mouse reports it
+    wheel: i8,
+    buttons: u8, // one bit per `BUTTONS` entry
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketFormat {
+    Standard,
+    Wheel,
+    FiveButtons,
+}
+
+impl PacketFormat {
+    fn size(self) -> usize {
+        match self {
+            PacketFormat::Standard => 3,
+            PacketFormat::Wheel | PacketFormat::FiveButtons => 4,
+        }
+    }
+}
+
+/// Collects bytes into packets.
+struct PacketParser {
+    format: PacketFormat,
+    bytes: [u8; 4],
+    received: usize,
+}
+
+impl PacketParser {
+    fn new(format: PacketFormat) -> PacketParser {
+        PacketParser { format, bytes: [0; 4], received: 0 }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<Packet> {
+        // Bit 3 of the first byte is always set, a byte without it where the
+        // first one should be means we lost track, so wait for one with it.
+        if self.received == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.received] = byte;
+        self.received += 1;
+        if self.received < self.format.size() {
+            return None;
+        }
+        self.received = 0;
+        decode(self.format, &self.bytes)
+    }
+}
+
+fn decode(format: PacketFormat, bytes: &[u8; 4]) -> Option<Packet> {
+    let flags = bytes[0];
+    if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+        return None; // the movement is garbage
+    }
+    // The movement is nine bit two's complement, the sign bits are in the
+    // first byte.
+    let extend = |value: u8, negative: bool| i16::from(value) - if negative { 0x100 } else { 0 };
+    let mut buttons = flags & (LEFT_BUTTON | RIGHT_BUTTON | MIDDLE_BUTTON);
+    let wheel = match format {
+        PacketFormat::Standard => 0,
+        PacketFormat::Wheel => bytes[3] as i8,
+        PacketFormat::FiveButtons => {
+            if bytes[3] & FOURTH_BUTTON != 0 {
+                buttons |= 1 << 3;
+            }
+            if bytes[3] & FIFTH_BUTTON != 0 {
+                buttons |= 1 << 4;
+            }
+            // Four bit two's complement.
+            ((bytes[3] << 4) as i8) >> 4
+        }
+    };
+    Some(Packet {
+        dx: extend(bytes[1], flags & X_SIGN != 0),
+        dy: extend(bytes[2], flags & Y_SIGN != 0),
+        wheel,
+        buttons,
+    })
+}
+
+struct MouseState {
+    parser: PacketParser,
+    buttons: u8,
+    // The cursor position in mouse counts, from the top left corner.
+    x: i32,
+    y: i32,
+}
+
+impl MouseState {
+    // Turns a packet into events, in the order they should be seen.
+    fn handle(&mut self, packet: Packet, mut emit: impl FnMut(MouseEvent)) {
+        if packet.dx != 0 || packet.dy != 0 {
+            emit(MouseEvent::Move { dx: packet.dx, dy: -packet.dy });
+            self.x = (self.x + i32::from(packet.dx)).max(0).min(COLUMNS * COUNTS_PER_COLUMN - 1);
+            self.y = (self.y - i32::from(packet.dy)).max(0).min(ROWS * COUNTS_PER_ROW - 1);
+        }
+        for &(button, bit) in BUTTONS.iter() {
+            match (self.buttons & bit != 0, packet.buttons & bit != 0) {
+                (false, true) => emit(MouseEvent::Press(button)),
+                (true, false) => emit(MouseEvent::Release(button)),
+                _ => {}
+            }
+        }
+        self.buttons = packet.buttons;
+        if packet.wheel != 0 {
+            emit(MouseEvent::Scroll(packet.wheel));
+        }
+    }
+
+    fn cell(&self) -> (usize, usize) {
+        ((self.y / COUNTS_PER_ROW) as usize, (self.x / COUNTS_PER_COLUMN) as usize)
+    }
+}
+
+/// Sets up the mouse on the second PS/2 port, if `i8042::init` found one,
+/// switching it to IntelliMouse mode when it supports that, and starts
+/// drawing the cursor.
+pub fn init() -> Result<(), I8042Error> {
+    let port = match i8042::info().and_then(|info| info.mouse_port()) {
+        Some(port) => port,
+        None => return Ok(()), // no mouse, nothing to do
+    };
+    let format = enable_extensions(port)?;
+    i8042::send_command(port, &[SET_SAMPLE_RATE, 100, ENABLE_REPORTING])?;
+
+    let state = MouseState {
+        parser: PacketParser::new(format),
+        buttons: 0,
+        // Start in the middle of the screen.
+        x: COLUMNS * COUNTS_PER_COLUMN / 2,
+        y: ROWS * COUNTS_PER_ROW / 2,
+    };
+    let cell = state.cell();
+    STATE.call_once(|| Mutex::new(state));
+    cpu_interrupts::without_interrupts(|| Console::shown().writer().lock().set_mouse_cursor(Some(cell)));
+    interrupts::register_irq(MOUSE_IRQ, mouse_irq).expect("mouse IRQ is already taken");
+    Ok(())
+}
+
+// A mouse that understands a magic sequence of sample rates changes its ID,
+// and starts sending four byte packets.
+fn enable_extensions(port: Ps2Port) -> Result<PacketFormat, I8042Error> {
+    i8042::send_command(port, &[SET_SAMPLE_RATE, 200, SET_SAMPLE_RATE, 100, SET_SAMPLE_RATE, 80])?;
+    if i8042::identify(port)? != DeviceType::ScrollMouse {
+        return Ok(PacketFormat::Standard);
+    }
+    i8042::send_command(port, &[SET_SAMPLE_RATE, 200, SET_SAMPLE_RATE, 200, SET_SAMPLE_RATE, 80])?;
+    match i8042::identify(port)? {
+        DeviceType::FiveButtonMouse => Ok(PacketFormat::FiveButtons),
+        _ => Ok(PacketFormat::Wheel),
+    }
+}
+
+fn mouse_irq(_line: u8) {
+    let byte = match i8042::read_output() {
+        Some((Ps2Port::Second, byte)) => byte,
+        _ => return,
+    };
+    let state = match STATE.wait() {
+        Some(state) => state,
+        None => return,
+    };
+    // Only ever locked here, and the handler can't interrupt itself.
+    let mut state = state.lock();
+    if let Some(packet) = state.parser.add_byte(byte) {
+        state.handle(packet, push_event);
+        // Interrupted code holding the writer would deadlock us, leave the
+        // cursor where it is until the next packet.
+        if let Some(mut writer) = Console::shown().writer().try_lock() {
+            writer.set_mouse_cursor(Some(state.cell()));
+        }
+    }
+}
+
+fn push_event(event: MouseEvent) {
+    if EVENTS.push(event).is_ok() {
+        WAKER.wake();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How many events were dropped because the queue was full.
+pub fn dropped_events() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The screen cell the mouse cursor is on, as (row, column), `None` without
+/// a mouse.
+pub fn position() -> Option<(usize, usize)> {
+    STATE.wait().map(|state| cpu_interrupts::without_interrupts(|| state.lock().cell()))
+}
+
+pub fn try_read_event() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+/// Waits for the next event, halting the CPU in between interrupts.
+pub fn read_event() -> MouseEvent {
+    loop {
+        if let Some(event) = try_read_event() {
+            return event;
+        }
+        // See `keyboard::read_key`.
+        cpu_interrupts::disable();
+        if EVENTS.is_empty() {
+            cpu_interrupts::enable_and_hlt();
+        } else {
+            cpu_interrupts::enable();
+        }
+    }
+}
+
+/// Mouse events as a stream that never ends, for use in tasks.
+pub struct MouseEventStream {
+    _private: (),
+}
+
+impl MouseEventStream {
+    pub fn new() -> MouseEventStream {
+        MouseEventStream { _private: () }
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<MouseEvent>> {
+        if let Some(event) = EVENTS.pop() {
+            return Poll::Ready(Some(event));
+        }
+        WAKER.register(context.waker());
+        match EVENTS.pop() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_decode_standard_packet() {
+    let mut parser = PacketParser::new(PacketFormat::Standard);
+    // Left button down, moved right by 5 and down by 3.
+    assert_eq!(parser.add_byte(ALWAYS_ONE | LEFT_BUTTON | Y_SIGN), None);
+    assert_eq!(parser.add_byte(5), None);
+    let packet = parser.add_byte(0xfd).unwrap();
+    assert_eq!(packet, Packet { dx: 5, dy: -3, wheel: 0, buttons: 1 });
+}
+
+#[test_case]
+fn test_decode_four_byte_packets() {
+    let bytes = [ALWAYS_ONE | X_SIGN, 0xff, 0, 0xff];
+    let wheel = decode(PacketFormat::Wheel, &bytes).unwrap();
+    assert_eq!((wheel.dx, wheel.wheel), (-1, -1));
+    let bytes = [ALWAYS_ONE, 0, 0, FIFTH_BUTTON | 0x01];
+    let five = decode(PacketFormat::FiveButtons, &bytes).unwrap();
+    assert_eq!((five.buttons, five.wheel), (1 << 4, 1));
+}
+
+#[test_case]
+fn test_parser_resyncs_and_drops_overflows() {
+    let mut parser = PacketParser::new(PacketFormat::Standard);
+    // A stray byte without bit 3 can't start a packet.
+    assert_eq!(parser.add_byte(0x00), None);
+    assert_eq!(parser.received, 0);
+    for &byte in [ALWAYS_ONE | X_OVERFLOW, 0xff, 0xff].iter() {
+        assert_eq!(parser.add_byte(byte), None);
+    }
+    assert_eq!(parser.received, 0);
+}
+
+#[test_case]
+fn test_packets_become_events() {
+    use alloc::vec::Vec;
+
+    let mut state = MouseState { parser: PacketParser::new(PacketFormat::Wheel), buttons: 1, x: 0, y: 0 };
+    let mut events = Vec::new();
+    let packet = Packet { dx: 16, dy: -32, wheel: 2, buttons: 0b010 };
+    state.handle(packet, |event| events.push(event));
+    assert_eq!(
+        events,
+        [
+            MouseEvent::Move { dx: 16, dy: 32 },
+            MouseEvent::Release(MouseButton::Left),
+            MouseEvent::Press(MouseButton::Right),
+            MouseEvent::Scroll(2),
+        ]
+    );
+    assert_eq!(state.cell(), (2, 2));
+}
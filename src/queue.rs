@@ -0,0 +1,110 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// A queue for handing data from an interrupt handler to the rest of the
+// kernel. The handler can't wait on a lock the code it interrupted might
+// hold, so pushing and popping only use atomics.
+
+/// How many items an `IrqQueue` holds before refusing new ones.
+pub const IRQ_QUEUE_SIZE: usize = 128;
+
+/// A fixed size ring that an interrupt handler pushes to without locking.
+/// `head` and `tail` count every item ever popped and pushed, so
+/// `tail - head` is how many are queued.
+///
+/// Only one context may push at a time, which holds for an interrupt
+/// handler since it can't interrupt itself. Any number may pop.
+pub struct IrqQueue<T> {
+    slots: UnsafeCell<MaybeUninit<[T; IRQ_QUEUE_SIZE]>>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// A slot is only written while it's outside `head..tail`, so no reader can
+// be looking at it, see `push` and `pop`.
+unsafe impl<T: Send> Sync for IrqQueue<T> {}
+
+impl<T> IrqQueue<T> {
+    pub const fn new() -> IrqQueue<T> {
+        IrqQueue {
+            slots: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T: Copy> IrqQueue<T> {
+    fn slot(&self, index: usize) -> *mut T {
+        unsafe { (self.slots.get() as *mut T).add(index % IRQ_QUEUE_SIZE) }
+    }
+
+    /// Queues `item`, or gives it back when the queue is full.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= IRQ_QUEUE_SIZE {
+            return Err(item);
+        }
+        unsafe { self.slot(tail).write_volatile(item) };
+        // Publishes the item to `pop`.
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            if head == self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            // The slot can't be reused until `head` moves past it, and if
+            // another reader moved it first the exchange fails and the item
+            // we read is thrown away.
+            let item = unsafe { self.slot(head).read_volatile() };
+            match self.head.compare_exchange_weak(head, head.wrapping_add(1), Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => return Some(item),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test_case]
+fn test_queue_is_fifo() {
+    let queue = IrqQueue::new();
+    assert_eq!(queue.pop(), None);
+    for item in 1..=3u8 {
+        queue.push(item).unwrap();
+    }
+    assert_eq!(queue.len(), 3);
+    assert_eq!((queue.pop(), queue.pop(), queue.pop(), queue.pop()), (Some(1), Some(2), Some(3), None));
+}
+
+#[test_case]
+fn test_queue_overflow_is_refused() {
+    let queue = IrqQueue::new();
+    for item in 0..IRQ_QUEUE_SIZE {
+        queue.push(item as u8).unwrap();
+    }
+    assert_eq!(queue.push(0xff), Err(0xff));
+    // Making room lets pushes through again, wrapping around the ring.
+    assert_eq!(queue.pop(), Some(0));
+    assert_eq!(queue.push(0xff), Ok(()));
+    assert_eq!(queue.len(), IRQ_QUEUE_SIZE);
+}
+
+#[test_case]
+fn test_queue_holds_structs() {
+    let queue = IrqQueue::new();
+    queue.push((1u8, -2i16)).unwrap();
+    assert_eq!(queue.pop(), Some((1, -2)));
+}
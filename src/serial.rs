@@ -1,6 +1,13 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::queue::IrqQueue;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
@@ -28,4 +35,78 @@ macro_rules! serial_println {
     () => ($crate::serial_println!("\n"));
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+// Input comes in on IRQ 4, which `uart_16550` turns on for received bytes.
+// Like the keyboard's, the handler only queues them. It reads the port
+// directly instead of going through `SERIAL1`, whose lock the code it
+// interrupted may be holding.
+
+/// The line COM1 interrupts on.
+pub const SERIAL_IRQ: u8 = 4;
+
+const COM1_DATA: u16 = 0x3f8;
+const COM1_LINE_STATUS: u16 = 0x3fd;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+
+static RECEIVED: IrqQueue<u8> = IrqQueue::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Sets up COM1, if printing hasn't already, and starts taking input.
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+    crate::interrupts::register_irq(SERIAL_IRQ, serial_irq).expect("serial IRQ is already taken");
+}
+
+fn serial_irq(_line: u8) {
+    let mut line_status: Port<u8> = Port::new(COM1_LINE_STATUS);
+    let mut data: Port<u8> = Port::new(COM1_DATA);
+    // The FIFO may hold several bytes by the time we get here.
+    while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        if RECEIVED.push(byte).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    WAKER.wake();
+}
+
+/// How many received bytes were dropped because nobody read them.
+pub fn dropped_bytes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+pub fn try_read_byte() -> Option<u8> {
+    RECEIVED.pop()
+}
+
+/// The bytes received on COM1, as a stream that never ends.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> SerialStream {
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        if let Some(byte) = RECEIVED.pop() {
+            return Poll::Ready(Some(byte));
+        }
+        // Registered before trying again, so a byte arriving in between
+        // still wakes us up.
+        WAKER.register(context.waker());
+        match RECEIVED.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
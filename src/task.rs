@@ -0,0 +1,97 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+pub mod executor;
+pub mod simple_executor;
+
+// Cooperative multitasking: a task is a future that runs until it has to
+// wait for something and returns `Poll::Pending`, and an executor polls it
+// again once its waker says whatever it waited on happened. No threads, no
+// stacks of their own, no preemption.
+
+/// A future an executor runs to completion.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Runs `future` to completion on the spot, halting the CPU while it waits.
+/// Meant for tests and for setup code that has no executor to spawn on.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        assert!(interrupts::are_enabled(), "block_on with interrupts disabled would never wake up");
+        // Same as `keyboard::read_key`, a wake-up between the check and the
+        // `hlt` must not be slept through.
+        interrupts::disable();
+        if woken.0.swap(false, Ordering::SeqCst) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn test_block_on_returns_the_output() {
+    assert_eq!(block_on(async { 6 * 7 }), 42);
+}
+
+#[test_case]
+fn test_block_on_waits_for_a_wake_up() {
+    // Pending once, waking itself up before it returns.
+    let mut polled = false;
+    let future = futures_util::future::poll_fn(|context| {
+        if polled {
+            Poll::Ready(())
+        } else {
+            polled = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    });
+    block_on(future);
+}
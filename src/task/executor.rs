@@ -0,0 +1,179 @@
+use super::{Task, TaskId};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+// Only polls a task again once its waker was called, and halts the CPU when
+// no task is ready. Wakers may be called from interrupt handlers, so they
+// only push the task's ID to a lock-free queue. A task is in the queue at
+// most once, and there are never more tasks than the queue holds, so a
+// waker never finds it full.
+
+const TASK_QUEUE_SIZE: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    // IDs of tasks that were woken up, and so need polling.
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `task`, to be polled on the next run. Panics past
+    /// `TASK_QUEUE_SIZE` tasks.
+    pub fn spawn(&mut self, task: Task) {
+        assert!(self.tasks.len() < TASK_QUEUE_SIZE, "more than {} tasks", TASK_QUEUE_SIZE);
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("task queue full");
+    }
+
+    /// Runs the tasks forever, sleeping whenever none of them is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Polls tasks until none is ready any more, then returns, whether or not
+    /// they are all done.
+    pub fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
+    /// Whether every spawned task has finished.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // Destructure `self` to avoid borrow checker errors.
+        let Self { tasks, task_queue, waker_cache } = self;
+
+        while let Ok(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let task_waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            // Off the queue now, so the next wake has to put it back.
+            task_waker.queued.store(false, Ordering::SeqCst);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    // Task done, remove it and its cached waker.
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // An interrupt could wake a task right after the check, so check
+        // with interrupts off and let `sti; hlt` turn them back on.
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // Whether the task is in the queue already, so waking it again does nothing.
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            queued: AtomicBool::new(false),
+        })
+    }
+
+    // Called from interrupt handlers too, so it must not panic.
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            // Can't be full, see the top of the file.
+            let _ = self.task_queue.push(self.task_id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn test_only_woken_tasks_run_again() {
+    use alloc::rc::Rc;
+    use core::cell::{Cell, RefCell};
+
+    let polls = Rc::new(Cell::new(0));
+    let saved_waker: Rc<RefCell<Option<Waker>>> = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    {
+        let (polls, saved_waker) = (polls.clone(), saved_waker.clone());
+        executor.spawn(Task::new(futures_util::future::poll_fn(move |context| {
+            polls.set(polls.get() + 1);
+            if polls.get() == 2 {
+                return Poll::Ready(());
+            }
+            *saved_waker.borrow_mut() = Some(context.waker().clone());
+            Poll::Pending
+        })));
+    }
+
+    executor.run_until_idle();
+    executor.run_until_idle();
+    assert_eq!(polls.get(), 1, "polled again without being woken");
+    saved_waker.borrow_mut().take().unwrap().wake();
+    executor.run_until_idle();
+    assert_eq!(polls.get(), 2);
+    assert!(executor.is_empty());
+}
+
+#[test_case]
+fn test_waking_twice_queues_once() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(futures_util::future::pending()));
+    executor.run_until_idle();
+    let waker = executor.waker_cache.values().next().unwrap().clone();
+    for _ in 0..2 * TASK_QUEUE_SIZE {
+        waker.clone().wake();
+    }
+    assert_eq!(executor.task_queue.len(), 1);
+    executor.run_until_idle();
+    assert!(executor.task_queue.is_empty());
+}
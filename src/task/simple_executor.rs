@@ -0,0 +1,65 @@
+use super::Task;
+use alloc::collections::VecDeque;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+// The simplest executor that works: poll every task in turn, over and over,
+// until they're all done. Wakers do nothing, so it keeps the CPU busy even
+// when every task is waiting, see `executor::Executor` for the one that
+// doesn't.
+
+pub struct SimpleExecutor {
+    task_queue: VecDeque<Task>,
+}
+
+impl SimpleExecutor {
+    pub fn new() -> SimpleExecutor {
+        SimpleExecutor { task_queue: VecDeque::new() }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        self.task_queue.push_back(task)
+    }
+
+    /// Polls the tasks round robin until every one of them has finished.
+    pub fn run(&mut self) {
+        while let Some(mut task) = self.task_queue.pop_front() {
+            let waker = dummy_waker();
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {} // task done
+                Poll::Pending => self.task_queue.push_back(task),
+            }
+        }
+    }
+}
+
+fn dummy_raw_waker() -> RawWaker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        dummy_raw_waker()
+    }
+
+    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
+    RawWaker::new(core::ptr::null::<()>(), vtable)
+}
+
+fn dummy_waker() -> Waker {
+    unsafe { Waker::from_raw(dummy_raw_waker()) }
+}
+
+#[test_case]
+fn test_runs_tasks_to_completion() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let finished = Rc::new(Cell::new(0));
+    let mut executor = SimpleExecutor::new();
+    for _ in 0..3 {
+        let finished = finished.clone();
+        executor.spawn(Task::new(async move {
+            finished.set(finished.get() + 1);
+        }));
+    }
+    executor.run();
+    assert_eq!(finished.get(), 3);
+}
@@ -0,0 +1,288 @@
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+pub use self::rtc::DateTime;
+
+// Kernel time, counted in timer ticks. The clock source (the PIT, or the
+// HPET's timer 0) raises IRQ 0 at a fixed rate and every interrupt bumps
+// `TICKS`. With the HPET, time between ticks comes from its main counter.
+
+/// The tick rate `init` programs.
+pub const DEFAULT_TICK_HZ: u32 = 1000;
+
+const FEMTOS_PER_SECOND: u128 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+/// What drives the timer interrupt and the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Pit,
+    Hpet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    /// There is no HPET to switch to.
+    NoHpet,
+    Hpet(hpet::HpetError),
+}
+
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+// The tick rate and the clock source can change at runtime, so time is
+// counted from the last change: `EPOCH_NANOS` had passed when `TICKS` was
+// `EPOCH_TICKS` and the HPET's counter was at `EPOCH_COUNTER`.
+static EPOCH_TICKS: AtomicU64 = AtomicU64::new(0);
+static EPOCH_NANOS: AtomicU64 = AtomicU64::new(0);
+static EPOCH_COUNTER: AtomicU64 = AtomicU64::new(0);
+// The RTC's time when `init` ran, as a Unix timestamp. The RTC only counts
+// whole seconds, so the wall clock runs on the ticks from there.
+static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the clock, on the source given as `clocksource=pit|hpet` on the
+/// kernel command line. That defaults to the PIT, because the HPET takes
+/// IRQ 8 away from the RTC, and falls back to it when there's no HPET.
+///
+/// The TSC is only calibrated, and so only used by `rdtsc_nanos`, when it's
+/// invariant. Otherwise its rate may change under us.
+pub fn init() {
+    BOOT_TIMESTAMP.store(rtc::read().to_unix_timestamp(), Ordering::SeqCst);
+    let has_hpet = hpet::init();
+    if !tsc::is_invariant() {
+        crate::println!("the TSC isn't invariant, timing with the clock ticks instead");
+    } else if has_hpet {
+        tsc::calibrate_against_hpet(hpet::hpet().unwrap());
+    } else {
+        tsc::calibrate();
+    }
+    set_tick_frequency(DEFAULT_TICK_HZ);
+
+    match crate::cmdline::get("clocksource") {
+        None | Some("pit") => {}
+        Some("hpet") => {
+            if set_clock_source(ClockSource::Hpet).is_err() {
+                crate::println!("clocksource=hpet: no usable HPET, staying on the PIT");
+            }
+        }
+        Some(other) => crate::println!("clocksource={}: unknown clock source, using the PIT", other),
+    }
+}
+
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::SeqCst) {
+        source if source == ClockSource::Hpet as u8 => ClockSource::Hpet,
+        _ => ClockSource::Pit,
+    }
+}
+
+/// Moves the timer interrupt and the monotonic clock over to `source`,
+/// keeping the tick rate. Time carries on from where the old source left it.
+pub fn set_clock_source(source: ClockSource) -> Result<(), TimeError> {
+    let hpet = hpet::hpet();
+    if source == ClockSource::Hpet && hpet.is_none() {
+        return Err(TimeError::NoHpet);
+    }
+    let hz = tick_frequency().max(1);
+    without_interrupts(|| {
+        let previous = clock_source();
+        start_epoch();
+        if let Some(hpet) = hpet {
+            hpet.disable_timer(0);
+            // Gives IRQ 0 to the HPET or back to the PIT.
+            hpet.set_legacy_replacement(source == ClockSource::Hpet);
+        }
+        CLOCK_SOURCE.store(source as u8, Ordering::SeqCst);
+        program_ticks(hz).or_else(|error| {
+            // Leave things the way they were.
+            CLOCK_SOURCE.store(previous as u8, Ordering::SeqCst);
+            if let Some(hpet) = hpet {
+                hpet.set_legacy_replacement(previous == ClockSource::Hpet);
+            }
+            program_ticks(hz)?;
+            Err(error)
+        })
+    })
+}
+
+/// The current date and time, the RTC's time at boot plus the uptime.
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(BOOT_TIMESTAMP.load(Ordering::SeqCst) + uptime().as_secs())
+}
+
+/// Reprograms the clock source to tick as close to `hz` times a second as
+/// it can, and returns the rate it actually ticks at, rounded to whole Hz.
+pub fn set_tick_frequency(hz: u32) -> u32 {
+    without_interrupts(|| {
+        start_epoch();
+        // Only fails when the HPET's timer 0 can't be periodic, and then we
+        // never switched to it in the first place.
+        program_ticks(hz).expect("the clock source can't tick");
+    });
+    tick_frequency()
+}
+
+/// How many times a second the timer ticks, rounded to whole Hz.
+pub fn tick_frequency() -> u32 {
+    match u128::from(TICK_PERIOD_FS.load(Ordering::SeqCst)) {
+        0 => 0,
+        period => ((FEMTOS_PER_SECOND + period / 2) / period) as u32,
+    }
+}
+
+// Must run with interrupts off, like everything touching the epoch.
+fn start_epoch() {
+    EPOCH_NANOS.store(nanos_since_boot(), Ordering::SeqCst);
+    EPOCH_TICKS.store(TICKS.load(Ordering::SeqCst), Ordering::SeqCst);
+    if let Some(hpet) = hpet::hpet() {
+        EPOCH_COUNTER.store(hpet.counter(), Ordering::SeqCst);
+    }
+}
+
+fn program_ticks(hz: u32) -> Result<(), TimeError> {
+    let period_fs = match clock_source() {
+        ClockSource::Pit => {
+            let divisor = pit::divisor_for(hz);
+            pit::set_divisor(divisor);
+            u128::from(divisor) * FEMTOS_PER_SECOND / u128::from(pit::BASE_FREQUENCY)
+        }
+        ClockSource::Hpet => {
+            let hpet = hpet::hpet().ok_or(TimeError::NoHpet)?;
+            let period_ns = 1_000_000_000 / u64::from(hz.max(1));
+            let period_ns = hpet.set_periodic(0, period_ns).map_err(TimeError::Hpet)?;
+            u128::from(period_ns) * FEMTOS_PER_NANO
+        }
+    };
+    TICK_PERIOD_FS.store(period_fs as u64, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Timer ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Nanoseconds since `init`, with the resolution of one tick on the PIT and
+/// of the main counter on the HPET. Never goes backwards, even when the tick
+/// rate or the clock source changes.
+pub fn monotonic_nanos() -> u64 {
+    without_interrupts(nanos_since_boot)
+}
+
+/// Time since `init`, see `monotonic_nanos`.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(monotonic_nanos())
+}
+
+// Must run with interrupts off, so the tick and the epoch can't change
+// halfway through.
+fn nanos_since_boot() -> u64 {
+    let nanos = match (clock_source(), hpet::hpet()) {
+        (ClockSource::Hpet, Some(hpet)) => {
+            hpet.counts_to_nanos(hpet.counter() - EPOCH_COUNTER.load(Ordering::SeqCst))
+        }
+        _ => {
+            let ticks = u128::from(TICKS.load(Ordering::SeqCst) - EPOCH_TICKS.load(Ordering::SeqCst));
+            (ticks * u128::from(TICK_PERIOD_FS.load(Ordering::SeqCst)) / FEMTOS_PER_NANO) as u64
+        }
+    };
+    EPOCH_NANOS.load(Ordering::SeqCst) + nanos
+}
+
+/// Nanoseconds since `init`, read from the TSC, so with far better than tick
+/// resolution. Falls back to `monotonic_nanos` before calibration, and when
+/// the TSC isn't invariant and never gets calibrated.
+pub fn rdtsc_nanos() -> u64 {
+    tsc::nanos().unwrap_or_else(monotonic_nanos)
+}
+
+/// Measures how long something takes, using the TSC. See `rdtsc_nanos`.
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    start: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Stopwatch {
+        Stopwatch { start: rdtsc_nanos() }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        // Saturating, as the start may have been taken on the tick clock
+        // before the TSC was calibrated, which can be ahead of the TSC.
+        Duration::from_nanos(rdtsc_nanos().saturating_sub(self.start))
+    }
+
+    /// Returns the time elapsed so far and starts counting from zero again.
+    pub fn lap(&mut self) -> Duration {
+        let now = rdtsc_nanos();
+        let lap = Duration::from_nanos(now.saturating_sub(self.start));
+        self.start = now;
+        lap
+    }
+}
+
+/// Spins until `count` more ticks have passed.
+pub fn sleep_ticks(count: u64) {
+    assert!(interrupts::are_enabled(), "sleep_ticks with interrupts disabled would never return");
+    let until = ticks() + count;
+    while ticks() < until {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let before = ticks();
+    sleep_ticks(3);
+    assert!(ticks() >= before + 3);
+}
+
+#[test_case]
+fn test_monotonic_nanos_follow_ticks() {
+    let before = monotonic_nanos();
+    sleep_ticks(10);
+    let elapsed = monotonic_nanos() - before;
+    // 10 ticks at 1000 Hz, give or take the tick we started in the middle of.
+    let tick = 1_000_000_000 / u64::from(tick_frequency());
+    assert!(elapsed >= 9 * tick && elapsed <= 11 * tick, "{} ns for 10 ticks", elapsed);
+}
+
+#[test_case]
+fn test_now_follows_the_rtc() {
+    // Both count whole seconds, so they may be a second apart.
+    let now = now().to_unix_timestamp();
+    let rtc = rtc::read().to_unix_timestamp();
+    assert!(now + 2 >= rtc && rtc + 2 >= now, "now() is {} s off", now as i64 - rtc as i64);
+}
+
+#[test_case]
+fn test_stopwatch() {
+    let mut stopwatch = Stopwatch::start();
+    sleep_ticks(10);
+    let lap = stopwatch.lap();
+    // About 10 ms, with lots of room for QEMU being slow.
+    assert!(lap >= Duration::from_millis(5) && lap <= Duration::from_millis(50), "{:?}", lap);
+    assert!(stopwatch.elapsed() < lap);
+}
+
+#[test_case]
+fn test_uptime_is_monotonic() {
+    let mut last = uptime();
+    for _ in 0..1000 {
+        let now = uptime();
+        assert!(now >= last);
+        last = now;
+    }
+}
@@ -0,0 +1,212 @@
+use crate::acpi;
+use crate::memory::paging::{kernel_page_table, PagingError};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+// The high precision event timer: a free running main counter, at 10 MHz or
+// more, and a handful of comparators that raise an interrupt when the
+// counter reaches them. Found through the ACPI `HPET` table and programmed
+// through memory mapped registers.
+
+static HPET: Once<Hpet> = Once::new();
+
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+const ENABLE: u64 = 1 << 0;
+// Timer 0 takes over IRQ 0 from the PIT and timer 1 takes over IRQ 8 from
+// the RTC, so neither needs routing of its own.
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NoSuchTimer(usize),
+    /// Only some comparators can repeat on their own.
+    PeriodicUnsupported(usize),
+    /// Only timers 0 and 1 can be used, through legacy replacement, which
+    /// has to be switched on first.
+    NotRoutable(usize),
+}
+
+pub struct Hpet {
+    base: VirtAddr,
+    period_fs: u64, // length of one counter tick in femtoseconds
+    timers: usize,
+}
+
+impl Hpet {
+    fn read(&self, register: u64) -> u64 {
+        // `base` is the HPET's register block, mapped uncached in `init`.
+        unsafe { (self.base + register).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&self, register: u64, value: u64) {
+        unsafe { (self.base + register).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+
+    fn timer_config(timer: usize) -> u64 {
+        0x100 + 0x20 * timer as u64
+    }
+
+    fn timer_comparator(timer: usize) -> u64 {
+        0x108 + 0x20 * timer as u64
+    }
+
+    /// The main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// How long one count of the main counter takes.
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn timers(&self) -> usize {
+        self.timers
+    }
+
+    pub fn counts_to_nanos(&self, counts: u64) -> u64 {
+        (u128::from(counts) * u128::from(self.period_fs) / FEMTOS_PER_NANO) as u64
+    }
+
+    pub fn nanos_to_counts(&self, nanos: u64) -> u64 {
+        (u128::from(nanos) * FEMTOS_PER_NANO / u128::from(self.period_fs)).max(1) as u64
+    }
+
+    pub fn legacy_replacement(&self) -> bool {
+        self.read(CONFIGURATION) & LEGACY_REPLACEMENT != 0
+    }
+
+    /// Switches timers 0 and 1 over to IRQ 0 and IRQ 8, which cuts the PIT
+    /// and the RTC off from their IRQs.
+    pub fn set_legacy_replacement(&self, enabled: bool) {
+        let config = self.read(CONFIGURATION);
+        let config = if enabled { config | LEGACY_REPLACEMENT } else { config & !LEGACY_REPLACEMENT };
+        self.write(CONFIGURATION, config);
+    }
+
+    fn check_timer(&self, timer: usize) -> Result<(), HpetError> {
+        if timer >= self.timers {
+            Err(HpetError::NoSuchTimer(timer))
+        } else if timer > 1 || !self.legacy_replacement() {
+            Err(HpetError::NotRoutable(timer))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Makes `timer` interrupt once, `delay_ns` from now.
+    pub fn set_one_shot(&self, timer: usize, delay_ns: u64) -> Result<(), HpetError> {
+        self.check_timer(timer)?;
+        self.write(Self::timer_config(timer), TIMER_INTERRUPT_ENABLE);
+        self.write(Self::timer_comparator(timer), self.counter() + self.nanos_to_counts(delay_ns));
+        Ok(())
+    }
+
+    /// Makes `timer` interrupt every `period_ns`, and returns the period it
+    /// actually got, which is a whole number of counter ticks.
+    pub fn set_periodic(&self, timer: usize, period_ns: u64) -> Result<u64, HpetError> {
+        self.check_timer(timer)?;
+        let config = self.read(Self::timer_config(timer));
+        if config & TIMER_PERIODIC_CAPABLE == 0 {
+            return Err(HpetError::PeriodicUnsupported(timer));
+        }
+        let period = self.nanos_to_counts(period_ns);
+        // The comparator of a periodic timer can only be set with the
+        // counter stopped: the first write sets the comparator, the one
+        // after `TIMER_VALUE_SET` the period it's advanced by.
+        let running = self.read(CONFIGURATION);
+        self.write(CONFIGURATION, running & !ENABLE);
+        self.write(
+            Self::timer_config(timer),
+            TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET,
+        );
+        self.write(Self::timer_comparator(timer), self.counter() + period);
+        self.write(Self::timer_comparator(timer), period);
+        self.write(CONFIGURATION, running | ENABLE);
+        Ok(self.counts_to_nanos(period))
+    }
+
+    pub fn disable_timer(&self, timer: usize) {
+        if timer < self.timers {
+            self.write(Self::timer_config(timer), 0);
+        }
+    }
+}
+
+/// Finds the HPET in the ACPI tables, maps it and starts its main counter.
+/// Returns `false` when there is none, QEMU has one unless started with
+/// `-no-hpet` or `-no-acpi`.
+pub fn init() -> bool {
+    match find() {
+        Ok(Some(hpet)) => {
+            HPET.call_once(|| hpet);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn find() -> Result<Option<Hpet>, PagingError> {
+    let table = match acpi::find_table(b"HPET") {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    // The register block's address is in a generic address structure at
+    // offset 40, whose address field is at offset 4 in it.
+    let phys = PhysAddr::new(unsafe { acpi::read_phys::<u64>(table + 44u64) });
+    let base = unsafe { kernel_page_table().map_mmio(phys, 0x400)? };
+
+    let mut hpet = Hpet { base, period_fs: 0, timers: 0 };
+    let capabilities = hpet.read(CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    hpet.timers = ((capabilities >> 8) & 0b1_1111) as usize + 1;
+    if hpet.period_fs == 0 {
+        return Ok(None); // not a working HPET
+    }
+    // Stop all comparators before the counter starts running.
+    for timer in 0..hpet.timers {
+        hpet.disable_timer(timer);
+    }
+    let config = hpet.read(CONFIGURATION);
+    hpet.write(CONFIGURATION, (config | ENABLE) & !LEGACY_REPLACEMENT);
+    Ok(Some(hpet))
+}
+
+/// The HPET, once `init` has found one.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.wait()
+}
+
+#[test_case]
+fn test_main_counter_runs() {
+    if let Some(hpet) = hpet() {
+        // At least 10 MHz says the spec.
+        assert!(hpet.frequency() >= 10_000_000);
+        let start = hpet.counter();
+        crate::time::sleep_ticks(2);
+        assert!(hpet.counter() > start);
+    }
+}
+
+#[test_case]
+fn test_nanos_conversion_round_trips() {
+    if let Some(hpet) = hpet() {
+        let counts = hpet.nanos_to_counts(1_000_000);
+        let nanos = hpet.counts_to_nanos(counts);
+        assert!(nanos <= 1_000_000 && nanos + hpet.counts_to_nanos(1) >= 1_000_000);
+    }
+}
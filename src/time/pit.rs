@@ -0,0 +1,87 @@
+use x86_64::instructions::port::Port;
+
+// The 8253/8254 programmable interval timer. Channel 0 is wired to IRQ 0 and
+// counts down from a 16 bit divisor at a fixed input clock, raising the IRQ
+// every time it wraps.
+
+/// The PIT's input clock in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 reads its output.
+const CHANNEL_2_CONTROL: u16 = 0x61;
+
+// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b00_11_010_0;
+// Channel 0, latch the current count so both bytes belong together.
+const CHANNEL_0_LATCH: u8 = 0b00_00_000_0;
+// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary.
+const CHANNEL_2_ONE_SHOT: u8 = 0b10_11_000_0;
+
+/// The divisor that gets closest to `hz`, within what 16 bits can hold.
+pub fn divisor_for(hz: u32) -> u32 {
+    let hz = hz.max(1);
+    ((BASE_FREQUENCY + hz / 2) / hz).max(1).min(0x1_0000)
+}
+
+/// Makes channel 0 fire at `BASE_FREQUENCY / divisor` Hz. A divisor of
+/// 0x10000 is written as 0, which the PIT reads as 65536.
+pub fn set_divisor(divisor: u32) {
+    assert!(divisor >= 1 && divisor <= 0x1_0000, "PIT divisor out of range");
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0);
+    unsafe {
+        command.write(CHANNEL_0_RATE_GENERATOR);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+/// The current count of channel 0, counting down towards the next IRQ.
+pub fn read_count() -> u16 {
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0);
+    unsafe {
+        command.write(CHANNEL_0_LATCH);
+        let low = channel_0.read();
+        let high = channel_0.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// Spins for `count` cycles of the PIT's clock, measured on channel 2, which
+/// needs no interrupts, and returns what `measure` returned at the start and
+/// at the end of the wait.
+///
+/// Used to calibrate other clocks, `measure` reads the one being calibrated.
+pub fn measure_wait<F: FnMut() -> u64>(count: u16, mut measure: F) -> (u64, u64) {
+    let mut control: Port<u8> = Port::new(CHANNEL_2_CONTROL);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2);
+    unsafe {
+        // Gate off and speaker off while we load the count, counting only
+        // starts once the gate goes up.
+        let value = control.read() & !0b11;
+        control.write(value);
+        command.write(CHANNEL_2_ONE_SHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = measure();
+        control.write(value | 0b01);
+        // The output goes high once the count runs out.
+        while control.read() & 0b10_0000 == 0 {}
+        let end = measure();
+        (start, end)
+    }
+}
+
+#[test_case]
+fn test_divisor_for() {
+    assert_eq!(divisor_for(1000), 1193);
+    assert_eq!(divisor_for(100), 11932);
+    assert_eq!(divisor_for(10), 0x1_0000); // slower than the PIT can go
+    assert_eq!(divisor_for(BASE_FREQUENCY * 2), 1);
+}
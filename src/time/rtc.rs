@@ -0,0 +1,294 @@
+use crate::interrupts::{self, IrqError};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+// The real-time clock in the CMOS, which keeps the date and time while the
+// machine is off. Its registers are read by writing the register number to
+// port 0x70 and then reading port 0x71.
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7; // status A
+const HOUR_24: u8 = 1 << 1; // status B
+const BINARY_MODE: u8 = 1 << 2; // status B
+const PERIODIC_INTERRUPT: u8 = 1 << 6; // status B
+const HOUR_PM: u8 = 1 << 7; // in the hours register, in 12 hour mode
+
+/// The line the RTC interrupts on, on the slave PIC.
+pub const RTC_IRQ: u8 = 8;
+
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time of day, in UTC as far as we know, the RTC has
+/// no idea which time zone it was set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), u32::from(self.month), u32::from(self.day));
+        days as u64 * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+        let seconds = timestamp % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Days since 1970-01-01 for a date in the proleptic Gregorian calendar, and
+// back, after Howard Hinnant's `days_from_civil`: counting years from March
+// puts the leap day at the end of the year.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = (if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        // Bit 7 of the index would mask NMIs, leave it clear.
+        index.write(register);
+        data.read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        // Bit 7 of the index would mask NMIs, leave it clear.
+        index.write(register);
+        data.write(value);
+    }
+}
+
+/// The clock registers exactly as the RTC holds them, before decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century_register.map_or(0, read_register),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Turns the registers into a date, given status register B, which says
+// whether they're in BCD and whether the hours are in 12 hour format.
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & BINARY_MODE != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+
+    // The PM flag sits in the hours register in either mode.
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour = match (hour, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (hour, true) => hour + 12,
+            (hour, false) => hour,
+        };
+    }
+
+    // Without a century register, assume we're in the 21st century.
+    let century = if raw.century == 0 { 20 } else { convert(raw.century) };
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(convert(raw.year)),
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+// The FADT says which CMOS register holds the century, if any does.
+fn century_register() -> Option<u8> {
+    let fadt = crate::acpi::find_table(b"FACP")?;
+    match unsafe { crate::acpi::read_phys::<u8>(fadt + 108u64) } {
+        0 => None,
+        register => Some(register),
+    }
+}
+
+/// Reads the current date and time from the RTC.
+pub fn read() -> DateTime {
+    let century = century_register();
+    without_interrupts(|| {
+        // The clock may tick over between two of our reads even when it
+        // wasn't updating at the start, so read until two reads agree.
+        let mut raw = read_raw(century);
+        loop {
+            let again = read_raw(century);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        decode(raw, read_register(STATUS_B))
+    })
+}
+
+/// Makes the RTC interrupt on IRQ 8 at `32768 >> (rate - 1)` Hz, for `rate`
+/// between 3 (8192 Hz) and 15 (2 Hz).
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), IrqError> {
+    assert!(rate >= 3 && rate <= 15, "RTC rates below 3 don't work reliably");
+    interrupts::register_irq(RTC_IRQ, periodic_interrupt)?;
+    without_interrupts(|| {
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        // Clear anything pending, or the RTC never raises the IRQ.
+        read_register(STATUS_C);
+    });
+    Ok(())
+}
+
+pub fn disable_periodic_interrupt() {
+    without_interrupts(|| {
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+    });
+    interrupts::unregister_irq(RTC_IRQ);
+}
+
+/// How many periodic interrupts the RTC has raised.
+pub fn periodic_interrupt_count() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+fn periodic_interrupt(_line: u8) {
+    PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    // The RTC won't interrupt again until status register C has been read.
+    read_register(STATUS_C);
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    // 2020-06-07 9:05:30 PM, in BCD.
+    let raw = RawTime { second: 0x30, minute: 0x05, hour: 0x09 | HOUR_PM, day: 0x07, month: 0x06, year: 0x20, century: 0x20 };
+    let time = decode(raw, 0);
+    assert_eq!(alloc::format!("{}", time), "2020-06-07 21:05:30");
+    let midnight = RawTime { hour: 0x12, ..raw };
+    assert_eq!(decode(midnight, 0).hour, 0);
+    let noon = RawTime { hour: 0x12 | HOUR_PM, ..raw };
+    assert_eq!(decode(noon, 0).hour, 12);
+}
+
+#[test_case]
+fn test_decode_binary_24_hour() {
+    let raw = RawTime { second: 59, minute: 59, hour: 23, day: 31, month: 12, year: 99, century: 0 };
+    let time = decode(raw, BINARY_MODE | HOUR_24);
+    assert_eq!(alloc::format!("{}", time), "2099-12-31 23:59:59");
+}
+
+#[test_case]
+fn test_unix_timestamps() {
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(epoch.to_unix_timestamp(), 0);
+    let leap_day = DateTime { year: 2020, month: 2, day: 29, hour: 12, minute: 0, second: 0 };
+    assert_eq!(leap_day.to_unix_timestamp(), 1_582_977_600);
+    assert_eq!(DateTime::from_unix_timestamp(1_582_977_600), leap_day);
+    assert_eq!(DateTime::from_unix_timestamp(951_868_800).day, 1); // 2000-03-01
+}
+
+#[test_case]
+fn test_read_is_plausible() {
+    let now = read();
+    assert!(now.year >= 2020);
+    assert!(now.month >= 1 && now.month <= 12);
+    assert!(now.day >= 1 && now.day <= 31);
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn test_periodic_interrupt() {
+    let before = periodic_interrupt_count();
+    enable_periodic_interrupt(6).expect("IRQ 8 is taken"); // 1024 Hz
+    crate::time::sleep_ticks(20);
+    disable_periodic_interrupt();
+    assert!(periodic_interrupt_count() > before, "the RTC never interrupted");
+}
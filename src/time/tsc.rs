@@ -0,0 +1,134 @@
+use super::hpet::Hpet;
+use super::pit;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// The time stamp counter counts CPU cycles since reset, and reading it takes
+// a few dozen cycles instead of the microseconds an I/O port read takes, so
+// it's what we use for fine-grained timestamps once we know its frequency.
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+// Each calibration run waits 10 ms on the PIT, or on the HPET.
+const CALIBRATION_PIT_CYCLES: u16 = 11_932;
+const CALIBRATION_NANOS: u64 = 10_000_000;
+const CALIBRATION_RUNS: usize = 3;
+
+/// Reads the time stamp counter.
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Whether the TSC ticks at a constant rate no matter the CPU's power state,
+/// so it can serve as a clock. Without this it may slow down when the CPU
+/// does, though QEMU's TSC is steady either way.
+pub fn is_invariant() -> bool {
+    use core::arch::x86_64::__cpuid;
+    const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0007
+        && unsafe { __cpuid(0x8000_0007) }.edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// Measures the TSC frequency against the PIT, and returns it in Hz.
+pub fn calibrate() -> u64 {
+    // Anything that delays us only makes a run longer, so the shortest run
+    // is the most accurate one.
+    let cycles = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let (start, end) = pit::measure_wait(CALIBRATION_PIT_CYCLES, read);
+            end - start
+        })
+        .min()
+        .unwrap_or(0);
+    let frequency = cycles * u64::from(pit::BASE_FREQUENCY) / u64::from(CALIBRATION_PIT_CYCLES);
+    set_frequency(frequency);
+    frequency
+}
+
+/// Measures the TSC frequency against the HPET's main counter, which is
+/// more precise than the PIT and quicker to read, and returns it in Hz.
+pub fn calibrate_against_hpet(hpet: &Hpet) -> u64 {
+    let counts = hpet.nanos_to_counts(CALIBRATION_NANOS);
+    let cycles = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let start_counter = hpet.counter();
+            let start = read();
+            while hpet.counter() - start_counter < counts {}
+            read() - start
+        })
+        .min()
+        .unwrap_or(0);
+    let frequency = u128::from(cycles) * 1_000_000_000 / u128::from(hpet.counts_to_nanos(counts));
+    set_frequency(frequency as u64);
+    frequency as u64
+}
+
+/// Records the TSC frequency measured some other way, and starts counting
+/// nanoseconds from now.
+pub fn set_frequency(hz: u64) {
+    TSC_AT_BOOT.store(read(), Ordering::SeqCst);
+    FREQUENCY.store(hz, Ordering::SeqCst);
+}
+
+/// The calibrated frequency in Hz, `None` before `calibrate`.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::SeqCst) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Converts a number of TSC cycles to nanoseconds.
+pub fn cycles_to_nanos(cycles: u64) -> Option<u64> {
+    let hz = frequency()?;
+    Some((u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64)
+}
+
+/// Nanoseconds since calibration.
+pub fn nanos() -> Option<u64> {
+    cycles_to_nanos(read() - TSC_AT_BOOT.load(Ordering::SeqCst))
+}
+
+#[test_case]
+fn test_tsc_is_calibrated() {
+    // The tests ask QEMU for an invariant TSC, but a CPU without one has to
+    // be left alone by `init`.
+    if !is_invariant() {
+        assert_eq!(frequency(), None);
+        return;
+    }
+    let hz = frequency().expect("the TSC was not calibrated");
+    // Anything from 100 MHz to 10 GHz is believable.
+    assert!(hz > 100_000_000 && hz < 10_000_000_000, "TSC runs at {} Hz", hz);
+}
+
+#[test_case]
+fn test_tsc_agrees_with_ticks() {
+    if frequency().is_none() {
+        return;
+    }
+    let (tsc_start, ticks_start) = (nanos().unwrap(), super::monotonic_nanos());
+    super::sleep_ticks(50);
+    let tsc = nanos().unwrap() - tsc_start;
+    let ticks = super::monotonic_nanos() - ticks_start;
+    // The tick clock is only good to a tick, and QEMU adds some noise.
+    assert!(tsc * 10 >= ticks * 8 && tsc * 10 <= ticks * 12, "TSC: {} ns, ticks: {} ns", tsc, ticks);
+}
+
+#[test_case]
+fn test_calibrate_against_the_pit() {
+    // The PIT's channel 2 counts down without interrupts, on any CPU, so
+    // this runs whether or not `init` calibrated. Calibrating starts the
+    // nanoseconds over, which only throws off this test's own timing.
+    let previous = frequency();
+    let hz = calibrate();
+    assert_eq!(frequency(), Some(hz));
+    assert!(hz > 100_000_000 && hz < 10_000_000_000, "TSC runs at {} Hz", hz);
+    if let Some(previous) = previous {
+        // `init` used the HPET if there is one, both should agree.
+        assert!(hz * 10 >= previous * 9 && hz * 10 <= previous * 11, "PIT: {} Hz, before: {} Hz", hz, previous);
+    }
+    // Leave the TSC unused if `init` did.
+    FREQUENCY.store(previous.unwrap_or(0), Ordering::SeqCst);
+}
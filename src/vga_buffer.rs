@@ -1,9 +1,20 @@
 use volatile::Volatile; // Allows us to mark thing as volatile
                         // and make them safe from compiler optimizations
                         // that may exclude non-deterministic results (side effects)
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
+
+mod ansi;
+mod cp437;
+mod cursor;
+mod virtual_console;
+
+pub use cursor::CursorShape;
+pub use virtual_console::{Console, CONSOLE_COUNT};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
@@ -36,6 +47,45 @@ impl ColorCode {
     }
 }
 
+// SGR numbers its eight colors in a different order from the VGA, then the
+// bright ones the same way.
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+// What SGR escape sequences change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    foreground: Color,
+    background: Color,
+    bold: bool, // shown as the bright version of the foreground
+}
+
+impl Attributes {
+    fn color_code(&self) -> ColorCode {
+        let mut color_code = ColorCode::new(self.foreground, self.background);
+        if self.bold {
+            color_code.0 |= 0x08;
+        }
+        color_code
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 struct ScreenChar {
@@ -43,8 +93,21 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// How many rows that scrolled off the top are kept, unless the
+/// `scrollback=` command line option says otherwise.
+pub const DEFAULT_SCROLLBACK_ROWS: usize = 200;
+/// The most rows of scrollback a console keeps. Each row is 160 bytes of
+/// heap, and every console has its own, so this is about 64 KB a console.
+pub const MAX_SCROLLBACK_ROWS: usize = 400;
+
+// Only a placeholder for filling row arrays before they're read into.
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0),
+};
 
 #[repr(transparent)]
 struct Buffer {
@@ -55,83 +118,531 @@ pub struct Writer {
     // Writes to the last line of a given row, shifting lines up upon
     // completion, pulling in foreground and backgrounds from the ColorCode
     // type.
+    // Output goes to the bottom row unless `set_cursor` moves it elsewhere.
+    row_position: usize,
     column_position: usize,
-    color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    cursor_shape: CursorShape,
+    color_code: ColorCode, // what `attributes` come to
+    attributes: Attributes,
+    default_attributes: Attributes, // what `ESC [ 0 m` goes back to
+    saved_cursor: (usize, usize, Attributes), // row, column and attributes
+    scroll_region: (usize, usize), // first and last rows that scroll
+    parser: ansi::Parser,
+    screen: Screen,
+    mouse_cursor: Option<(usize, usize)>, // (row, column) of the cell shown inverted
+    scrollback: Scrollback,
+}
+
+// Where a writer's characters go: to the VGA when it's the console that's
+// shown, to memory of its own when it isn't.
+enum Screen {
+    Vga(&'static mut Buffer),
+    OffScreen(Box<[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]>),
+}
+
+impl Screen {
+    fn vga() -> Screen {
+        Screen::Vga(unsafe { &mut *(0xb8000 as *mut Buffer) })
+    }
 }
+
+// Rows that scrolled off the top of the screen, and how far back through
+// them the screen is showing.
+struct Scrollback {
+    rows: VecDeque<[ScreenChar; BUFFER_WIDTH]>, // oldest first
+    depth: usize, // how many rows to keep at most
+    offset: usize, // rows back from the bottom, 0 when showing live output
+    // What the screen showed before scrolling back, put back on the way
+    // down. Taken from the heap with the depth, because scrolling happens in
+    // the keyboard interrupt, which mustn't allocate.
+    live: Option<Box<[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]>>,
+}
+
+impl Scrollback {
+    fn new() -> Scrollback {
+        // Nothing is kept until a depth is set, there's no heap to keep it
+        // on before that.
+        Scrollback {
+            rows: VecDeque::new(),
+            depth: 0,
+            offset: 0,
+            live: None,
+        }
+    }
+}
+
 impl Writer {
+    fn new(foreground: Color, background: Color, screen: Screen) -> Writer {
+        let attributes = Attributes {
+            foreground,
+            background,
+            bold: false,
+        };
+        Writer {
+            row_position: BUFFER_HEIGHT - 1,
+            column_position: 0,
+            cursor_shape: CursorShape::Underline,
+            color_code: attributes.color_code(),
+            attributes,
+            default_attributes: attributes,
+            saved_cursor: (BUFFER_HEIGHT - 1, 0, attributes),
+            scroll_region: (0, BUFFER_HEIGHT - 1),
+            parser: ansi::Parser::new(),
+            screen,
+            mouse_cursor: None,
+            scrollback: Scrollback::new(),
+        }
+    }
+
+    /// Writes `byte` as it is, with no escape sequences, only `\n` starts a
+    /// new line.
     pub fn write_byte(&mut self, byte: u8) {
+        self.scroll_to_bottom(); // new output snaps the screen back to it.
+        self.put_byte(byte);
+        self.update_cursor();
+    }
+
+    fn put_byte(&mut self, byte: u8) {
         // Method to modify characters VGA buffer, and write single
         // ascii bytes.
         match byte {
             b'\n' => self.new_line(), // return newline at end of row.
-            byte => { // if we have a byte of text,
-                if self.column_position >= BUFFER_WIDTH { // we want to start it on a newline, if the column position
-                                                        // is greater than the length of a buffer row
-                    self.new_line();
-                }
-                
-                let row = BUFFER_HEIGHT - 1; // We want to select the current row, as it is somewhat empty, and available for bytes.
-                let col = self.column_position; // We select the current column position, as we want to write our char to the immediately available slot.
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code: color_code,
-                });
-                self.column_position += 1
-            }
+            byte => self.put_glyph(byte), // if we have a byte of text,
         }
     }
+
+    // Draws glyph `glyph` at the cursor and moves past it. Every byte is a
+    // glyph here, 0x0a included, so this never starts a new line unless
+    // the row is full.
+    fn put_glyph(&mut self, glyph: u8) {
+        if self.column_position >= BUFFER_WIDTH { // we want to start it on a newline, if the column position
+                                                // is greater than the length of a buffer row
+            self.new_line();
+        }
+
+        let row = self.row_position; // We want to select the current row, as it is somewhat empty, and available for bytes.
+        let col = self.column_position; // We select the current column position, as we want to write our char to the immediately available slot.
+        let color_code = self.color_code;
+        self.write_cell(row, col, ScreenChar {
+            ascii_character: glyph,
+            color_code: color_code,
+        });
+        self.column_position += 1
+    }
     
+    /// Writes `s`, following the VT100 escape sequences in it: moving the
+    /// cursor, clearing, saving and restoring the cursor, scroll regions,
+    /// and these SGR (`ESC [ n m`) ones for colors:
+    ///
+    /// - 0 goes back to the default colors, 1 is bold and 22 isn't
+    /// - 30 to 37 and 40 to 47 set the foreground and background
+    /// - 90 to 97 and 100 to 107 set them to the bright colors
+    /// - 39 and 49 go back to the default foreground and background
+    ///
+    /// A sequence can be split across calls.
     pub fn write_string(&mut self, s: &str) {
         // Allows us to print strings to the VGA buffer,
         // by converting them into a sequence of bytes, that we
         // print one by one.
-        for byte in s.bytes() {
-            match byte {
-                // covers ascii byte range via hexadecimal.
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe) // Rust strings are utf-8, so we have to catch any characters
-                                            // that are outside of the range VGA can display
+        self.scroll_to_bottom();
+        for character in s.chars() {
+            if let Some(action) = self.parser.advance(character) {
+                self.perform(action);
             }
         }
+        // Moving the hardware cursor is slow port I/O, so only once per string.
+        self.update_cursor();
     }
+
+    // Does what the bytes going through the escape sequence parser add up to.
+    fn perform(&mut self, action: ansi::Action) {
+        use ansi::Action;
+        let (top, bottom) = self.scroll_region;
+        match action {
+            Action::Print(character) => match character {
+                '\n' => self.new_line(),
+                '\r' => self.column_position = 0,
+                '\x08' => self.column_position = self.column_position.min(BUFFER_WIDTH - 1).saturating_sub(1), // backspace
+                // Rust strings are utf-8, so we look up which of the VGA's
+                // glyphs each character is, one `REPLACEMENT` if none is.
+                // '◙' is glyph 0x0a, so the glyph goes around `put_byte`.
+                character => self.put_glyph(cp437::glyph_or_replacement(character)),
+            },
+            // Up and down stop at the edges of the scroll region when they
+            // start inside it, like on a VT100.
+            Action::CursorUp(count) => {
+                let limit = if self.row_position >= top { top } else { 0 };
+                self.row_position = self.row_position.saturating_sub(count).max(limit);
+            }
+            Action::CursorDown(count) => {
+                let limit = if self.row_position <= bottom { bottom } else { BUFFER_HEIGHT - 1 };
+                self.row_position = (self.row_position + count).min(limit);
+            }
+            Action::CursorForward(count) => {
+                self.column_position = (self.column_position + count).min(BUFFER_WIDTH - 1);
+            }
+            Action::CursorBack(count) => {
+                self.column_position = self.column_position.min(BUFFER_WIDTH - 1).saturating_sub(count);
+            }
+            Action::CursorTo { row, col } => {
+                self.row_position = row.min(BUFFER_HEIGHT - 1);
+                self.column_position = col.min(BUFFER_WIDTH - 1);
+            }
+            Action::CursorToColumn(col) => self.column_position = col.min(BUFFER_WIDTH - 1),
+            Action::EraseDisplay(erase) => self.erase_display(erase),
+            Action::EraseLine(erase) => self.erase_line(erase),
+            Action::SaveCursor => {
+                self.saved_cursor = (self.row_position, self.column_position, self.attributes);
+            }
+            Action::RestoreCursor => {
+                let (row, col, attributes) = self.saved_cursor;
+                self.row_position = row;
+                self.column_position = col;
+                self.set_attributes(attributes);
+            }
+            Action::SetScrollRegion { top, bottom } => {
+                let bottom = bottom.unwrap_or(BUFFER_HEIGHT - 1).min(BUFFER_HEIGHT - 1);
+                if top < bottom {
+                    self.scroll_region = (top, bottom);
+                    self.row_position = 0;
+                    self.column_position = 0;
+                }
+            }
+            Action::SelectGraphicRendition(parameters) => {
+                let mut attributes = self.attributes;
+                for parameter in parameters.iter() {
+                    match parameter {
+                        0 => attributes = self.default_attributes,
+                        1 => attributes.bold = true,
+                        22 => attributes.bold = false,
+                        30..=37 => attributes.foreground = ANSI_COLORS[parameter as usize - 30],
+                        39 => attributes.foreground = self.default_attributes.foreground,
+                        40..=47 => attributes.background = ANSI_COLORS[parameter as usize - 40],
+                        49 => attributes.background = self.default_attributes.background,
+                        90..=97 => attributes.foreground = ANSI_COLORS[parameter as usize - 90 + 8],
+                        100..=107 => attributes.background = ANSI_COLORS[parameter as usize - 100 + 8],
+                        _ => {}
+                    }
+                }
+                self.set_attributes(attributes);
+            }
+        }
+    }
+
+    fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes;
+        self.color_code = attributes.color_code();
+    }
+
+    fn erase_display(&mut self, erase: ansi::Erase) {
+        self.erase_line(erase);
+        let rows = match erase {
+            ansi::Erase::ToEnd => self.row_position + 1..BUFFER_HEIGHT,
+            ansi::Erase::ToStart => 0..self.row_position,
+            ansi::Erase::All => 0..BUFFER_HEIGHT,
+        };
+        for row in rows {
+            self.clear_row(row);
+        }
+    }
+
+    fn erase_line(&mut self, erase: ansi::Erase) {
+        let columns = match erase {
+            ansi::Erase::ToEnd => self.column_position..BUFFER_WIDTH,
+            ansi::Erase::ToStart => 0..(self.column_position + 1).min(BUFFER_WIDTH),
+            ansi::Erase::All => 0..BUFFER_WIDTH,
+        };
+        self.blank_cells(self.row_position, columns);
+    }
+
     fn new_line(&mut self) {
         // Function to reset cursor position to the beginning of the
         // next available row in our buffer.
-        for row in 1..BUFFER_HEIGHT {
+        self.column_position = 0;
+        let (top, bottom) = self.scroll_region;
+        if self.row_position == bottom {
+            self.scroll_up(top, bottom);
+        } else if self.row_position < BUFFER_HEIGHT - 1 {
+            // Above the bottom of the scroll region, or below it where
+            // nothing scrolls, there's no need to scroll.
+            self.row_position += 1;
+        }
+    }
+
+    // Moves rows `top + 1` to `bottom` up by one, and blanks `bottom`.
+    fn scroll_up(&mut self, top: usize, bottom: usize) {
+        // The top row is about to go, keep it in the scrollback. Room for
+        // `depth` rows was reserved up front, so this never allocates.
+        if top == 0 && self.scrollback.depth > 0 {
+            if self.scrollback.rows.len() == self.scrollback.depth {
+                self.scrollback.rows.pop_front();
+            }
+            let top = self.read_row(0);
+            self.scrollback.rows.push_back(top);
+        }
+        for row in top + 1..=bottom {
             for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+                let character = self.read_cell(row, col);
+                self.write_cell(row - 1, col, character);
             }
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+        self.clear_row(bottom);
+    }
+    /// The column the next character goes to, on the cursor's row.
+    pub fn column_position(&self) -> usize {
+        self.column_position
+    }
+
+    /// Moves where the next character goes along the cursor's row, for
+    /// rewriting what's already there, like the line editor does.
+    pub fn set_column_position(&mut self, column: usize) {
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.update_cursor();
+    }
+
+    /// Moves where the next character goes to `row` and `col`, for output
+    /// at a fixed place on the screen. Text carries on from there, a new
+    /// line only scrolls the screen once it reaches the bottom row.
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH);
+        self.update_cursor();
+    }
+
+    /// The row and column the next character goes to.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.cursor_shape = shape;
+        self.show_cursor();
+    }
+
+    pub fn cursor_shape(&self) -> CursorShape {
+        self.cursor_shape
+    }
+
+    // Gives the hardware cursor this writer's shape and puts it in place,
+    // if this writer is on the screen. Where the cursor is means nothing in
+    // the scrollback, so it's hidden there.
+    fn show_cursor(&self) {
+        if !self.is_shown() {
+            return;
+        }
+        if self.scrollback.offset == 0 {
+            cursor::set_shape(self.cursor_shape);
+        } else {
+            cursor::set_shape(CursorShape::Hidden);
+        }
+        self.update_cursor();
+    }
+
+    // Puts the hardware cursor where the next character goes. After the end
+    // of a row that's the start of the next one.
+    fn update_cursor(&self) {
+        if !self.is_shown() || self.scrollback.offset != 0 || self.cursor_shape == CursorShape::Hidden {
+            return;
+        }
+        if self.column_position < BUFFER_WIDTH {
+            cursor::move_to(self.row_position, self.column_position);
+        } else if self.row_position < BUFFER_HEIGHT - 1 {
+            cursor::move_to(self.row_position + 1, 0);
+        } else {
+            cursor::move_to(self.row_position, BUFFER_WIDTH - 1);
+        }
+    }
+
+    /// Blanks the cursor's row from the column position to its end.
+    pub fn clear_to_end_of_row(&mut self) {
+        self.scroll_to_bottom();
+        let mouse_cursor = self.mouse_cursor;
+        self.set_mouse_cursor(None);
+        self.erase_line(ansi::Erase::ToEnd);
+        self.set_mouse_cursor(mouse_cursor);
+    }
+
+    /// Shows the mouse cursor as an inverted cell at `row` and `column`, or
+    /// hides it with `None`. Positions off the screen are clamped to it.
+    pub fn set_mouse_cursor(&mut self, position: Option<(usize, usize)>) {
+        let position = position.map(|(row, col)| (row.min(BUFFER_HEIGHT - 1), col.min(BUFFER_WIDTH - 1)));
+        if position == self.mouse_cursor {
+            return;
+        }
+        if let Some((row, col)) = self.mouse_cursor {
+            self.invert_cell(row, col);
+        }
+        if let Some((row, col)) = position {
+            self.invert_cell(row, col);
+        }
+        self.mouse_cursor = position;
+    }
+
+    pub fn mouse_cursor(&self) -> Option<(usize, usize)> {
+        self.mouse_cursor
+    }
+
+    // Swaps a cell's foreground and background colors, doing it twice
+    // puts them back.
+    fn invert_cell(&mut self, row: usize, col: usize) {
+        let mut character = self.read_cell(row, col);
+        character.color_code = ColorCode(character.color_code.0.rotate_left(4));
+        self.write_cell(row, col, character);
+    }
+
+    /// Keeps up to `rows` rows that scroll off the top of the screen, but no
+    /// more than `MAX_SCROLLBACK_ROWS`, dropping the oldest ones if more
+    /// than that are kept already.
+    ///
+    /// Takes the memory for all of them from the heap right away, so
+    /// scrolling through them never allocates.
+    pub fn set_scrollback_depth(&mut self, rows: usize) {
+        let rows = rows.min(MAX_SCROLLBACK_ROWS);
+        self.scroll_to_bottom();
+        let scrollback = &mut self.scrollback;
+        while scrollback.rows.len() > rows {
+            scrollback.rows.pop_front();
+        }
+        scrollback.rows.shrink_to_fit();
+        scrollback.rows.reserve_exact(rows - scrollback.rows.len());
+        scrollback.depth = rows;
+        if rows == 0 {
+            scrollback.live = None;
+        } else if scrollback.live.is_none() {
+            scrollback.live = Some(Box::new([[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]));
+        }
+    }
+
+    pub fn scrollback_depth(&self) -> usize {
+        self.scrollback.depth
+    }
+
+    /// How many rows back from the live output the screen is showing.
+    pub fn scroll_offset(&self) -> usize {
+        self.scrollback.offset
+    }
+
+    /// Shows `rows` rows further back in the scrollback, or as far back as
+    /// it goes.
+    pub fn scroll_back(&mut self, rows: usize) {
+        let offset = (self.scrollback.offset + rows).min(self.scrollback.rows.len());
+        self.scroll_to(offset);
+    }
+
+    /// Shows `rows` rows further forward, towards the live output.
+    pub fn scroll_forward(&mut self, rows: usize) {
+        let offset = self.scrollback.offset.saturating_sub(rows);
+        self.scroll_to(offset);
+    }
+
+    /// Goes back to showing the live output.
+    pub fn scroll_to_bottom(&mut self) {
+        if self.scrollback.offset != 0 {
+            self.scroll_to(0);
+        }
+    }
+
+    fn scroll_to(&mut self, offset: usize) {
+        if offset == self.scrollback.offset {
+            return;
+        }
+        // There's only somewhere to keep the live screen once a depth is
+        // set, and nothing to scroll back to before that.
+        let mut live = match self.scrollback.live.take() {
+            Some(live) => live,
+            None => return,
+        };
+        let mouse_cursor = self.mouse_cursor;
+        self.set_mouse_cursor(None);
+        if self.scrollback.offset == 0 {
+            for (row, characters) in live.iter_mut().enumerate() {
+                *characters = self.read_row(row);
+            }
+        }
+        self.scrollback.offset = offset;
+        // Row `i` of the scrollback followed by the live screen, counting
+        // from the oldest, is at the top of the screen.
+        let history = self.scrollback.rows.len();
+        let top = history - offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = top + row;
+            let characters = if line < history {
+                self.scrollback.rows[line]
+            } else {
+                live[line - history]
+            };
+            self.write_row(row, &characters);
+        }
+        self.scrollback.live = Some(live);
+        self.show_cursor();
+        self.set_mouse_cursor(mouse_cursor);
+    }
+
+    /// Whether this writer is the console on the screen.
+    pub fn is_shown(&self) -> bool {
+        match self.screen {
+            Screen::Vga(_) => true,
+            Screen::OffScreen(_) => false,
+        }
+    }
+
+    fn read_cell(&self, row: usize, col: usize) -> ScreenChar {
+        match &self.screen {
+            Screen::Vga(buffer) => buffer.chars[row][col].read(),
+            Screen::OffScreen(cells) => cells[row][col],
+        }
+    }
+
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        match &mut self.screen {
+            Screen::Vga(buffer) => buffer.chars[row][col].write(character),
+            Screen::OffScreen(cells) => cells[row][col] = character,
+        }
+    }
+
+    fn read_row(&self, row: usize) -> [ScreenChar; BUFFER_WIDTH] {
+        let mut characters = [BLANK; BUFFER_WIDTH];
+        for col in 0..BUFFER_WIDTH {
+            characters[col] = self.read_cell(row, col);
+        }
+        characters
+    }
+
+    fn write_row(&mut self, row: usize, characters: &[ScreenChar; BUFFER_WIDTH]) {
+        for col in 0..BUFFER_WIDTH {
+            self.write_cell(row, col, characters[col]);
+        }
     }
+
     fn clear_row(&mut self, row: usize) {
+        self.blank_cells(row, 0..BUFFER_WIDTH);
+    }
+
+    fn blank_cells(&mut self, row: usize, columns: core::ops::Range<usize>) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in columns {
+            self.write_cell(row, col, blank);
         }
     }
 }
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        // The text would scroll away under the mouse cursor, or overwrite
+        // it, so take it off the screen while writing.
+        let mouse_cursor = self.mouse_cursor;
+        self.set_mouse_cursor(None);
         self.write_string(s);
+        self.set_mouse_cursor(mouse_cursor);
         Ok(())
     }
 }
 pub fn print_something() {
     use core::fmt::Write;
-    let mut writer = Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Magenta, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer)}
-    };
+    let mut writer = Writer::new(Color::Magenta, Color::Black, Screen::vga());
     writer.write_byte(b'H');
     writer.write_string("IF A DOG CHEWS SHOES WHOSE SHOES DOES HE CHOOSE?");
     write!(writer, "The numbers are {} and {}", 42, 1.0/3.0).unwrap();
@@ -141,16 +652,84 @@ lazy_static! {
     // Delays initialization of a static value until it is
     // referenced, allowing us to do more set up in the initialization,
     // and read run-time values.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        // We use a mutex here for interior mutability, so that
-        // we want mutate our writers contents, without having to
-        // make the entire structure mutable.
-        // This mutex initiates a spinlock (which doesn't depend on blocking threads)
-        // which initiates a tight loop of continuous locking to deny access
-        // until the mutex is free again.
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe {&mut *(0xb8000 as *mut Buffer)},
+    // We use a mutex here for interior mutability, so that
+    // we want mutate our writers contents, without having to
+    // make the entire structure mutable.
+    // This mutex initiates a spinlock (which doesn't depend on blocking threads)
+    // which initiates a tight loop of continuous locking to deny access
+    // until the mutex is free again.
+    // It's the first of the virtual consoles, see `Console`.
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(Color::Yellow, Color::Black, Screen::vga()));
+}
+
+/// Sets up the virtual consoles, each keeping as many rows of scrollback as
+/// the `scrollback=` command line option says, or `DEFAULT_SCROLLBACK_ROWS`,
+/// and shows the hardware cursor where output goes. Needs the heap.
+pub fn init() {
+    use x86_64::instructions::interrupts;
+    disable_blinking();
+    let rows = match crate::cmdline::get("scrollback").map(|value| (value, value.parse::<usize>())) {
+        None => DEFAULT_SCROLLBACK_ROWS,
+        Some((_, Ok(rows))) if rows <= MAX_SCROLLBACK_ROWS => rows,
+        Some((value, Ok(_))) => {
+            println!("scrollback={}: more than {} rows, keeping {}", value, MAX_SCROLLBACK_ROWS, MAX_SCROLLBACK_ROWS);
+            MAX_SCROLLBACK_ROWS
+        }
+        Some((value, Err(_))) => {
+            println!("scrollback={}: not a number of rows, keeping {}", value, DEFAULT_SCROLLBACK_ROWS);
+            DEFAULT_SCROLLBACK_ROWS
+        }
+    };
+    interrupts::without_interrupts(|| {
+        for console in Console::all() {
+            let mut writer = console.writer().lock();
+            writer.set_scrollback_depth(rows);
+            let shape = writer.cursor_shape();
+            writer.set_cursor_shape(shape);
+        }
+    });
+}
+
+// The top bit of a cell's background color makes it blink, unless the
+// attribute controller is told to use it for bright backgrounds instead.
+fn disable_blinking() {
+    use x86_64::instructions::port::{Port, PortReadOnly};
+    const INPUT_STATUS: u16 = 0x3da;
+    const ATTRIBUTE_INDEX: u16 = 0x3c0; // also where values are written
+    const ATTRIBUTE_READ: u16 = 0x3c1;
+    const MODE_CONTROL: u8 = 0x10 | 0x20; // the register, with the screen left on
+    const BLINK_ENABLE: u8 = 1 << 3;
+    unsafe {
+        // Reading the status makes the next write to 0x3c0 an index.
+        PortReadOnly::<u8>::new(INPUT_STATUS).read();
+        let mut attribute: Port<u8> = Port::new(ATTRIBUTE_INDEX);
+        attribute.write(MODE_CONTROL);
+        let mode = PortReadOnly::<u8>::new(ATTRIBUTE_READ).read();
+        attribute.write(mode & !BLINK_ENABLE);
+    }
+}
+
+/// Scrolls the console on the screen back half a screen, for Shift+PageUp.
+///
+/// The keyboard interrupt calls this, so it does nothing rather than wait
+/// when the console is busy.
+pub fn page_up() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        if let Some(mut writer) = Console::shown().writer().try_lock() {
+            writer.scroll_back(BUFFER_HEIGHT / 2);
+        }
+    });
+}
+
+/// Scrolls it forward half a screen, for Shift+PageDown. Like `page_up`,
+/// does nothing when the console is busy.
+pub fn page_down() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        if let Some(mut writer) = Console::shown().writer().try_lock() {
+            writer.scroll_forward(BUFFER_HEIGHT / 2);
+        }
     });
 }
 
@@ -197,8 +776,224 @@ fn test_println_output() {
         let mut writer = WRITER.lock();
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate() {
-            let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.read_cell(BUFFER_HEIGHT - 2, i);
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     })
 }
+
+#[test_case]
+fn test_mouse_cursor_inverts_one_cell() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.mouse_cursor();
+        writer.set_mouse_cursor(None);
+        let before = writer.read_cell(3, 5);
+        writer.set_mouse_cursor(Some((3, 5)));
+        let inverted = writer.read_cell(3, 5);
+        assert_eq!(inverted.ascii_character, before.ascii_character);
+        assert_eq!(inverted.color_code.0, before.color_code.0 >> 4 | before.color_code.0 << 4);
+        writer.set_mouse_cursor(None);
+        assert_eq!(writer.read_cell(3, 5), before);
+        writer.set_mouse_cursor(previous);
+    });
+}
+
+#[test_case]
+fn test_scrollback_shows_earlier_rows() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let depth = writer.scrollback_depth();
+        writer.set_scrollback_depth(BUFFER_HEIGHT * 2);
+        for i in 0..BUFFER_HEIGHT * 2 {
+            writeln!(writer, "scrollback row {}", i).unwrap();
+        }
+        let live = writer.read_row(0);
+        // Rows 26 to 49 are on the screen above the empty bottom row, 24 and
+        // 25 are the last ones in the scrollback.
+        writer.scroll_back(2);
+        assert_eq!(writer.scroll_offset(), 2);
+        assert_eq!(writer.read_cell(2, 0), live[0]);
+        let digit = writer.read_cell(0, 15).ascii_character;
+        assert_eq!(digit, b'2');
+        writer.scroll_back(BUFFER_HEIGHT * 10);
+        assert_eq!(writer.scroll_offset(), BUFFER_HEIGHT * 2);
+        writer.scroll_forward(BUFFER_HEIGHT * 2 - 1);
+        assert_eq!(writer.read_cell(1, 0), live[0]);
+        // New output snaps back to the bottom.
+        write!(writer, "x").unwrap();
+        assert_eq!(writer.scroll_offset(), 0);
+        assert_eq!(writer.read_row(0), live);
+        writeln!(writer).unwrap();
+        writer.set_scrollback_depth(depth);
+    });
+}
+
+#[test_case]
+fn test_scrollback_depth_drops_oldest_rows() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let depth = writer.scrollback_depth();
+        writer.set_scrollback_depth(3);
+        for _ in 0..10 {
+            writer.write_byte(b'\n');
+        }
+        writer.scroll_back(10);
+        assert_eq!(writer.scroll_offset(), 3);
+        writer.set_scrollback_depth(1);
+        assert_eq!(writer.scroll_offset(), 0);
+        writer.scroll_back(10);
+        assert_eq!(writer.scroll_offset(), 1);
+        writer.scroll_to_bottom();
+        writer.set_scrollback_depth(usize::MAX);
+        assert_eq!(writer.scrollback_depth(), MAX_SCROLLBACK_ROWS);
+        writer.set_scrollback_depth(depth);
+    });
+}
+
+#[test_case]
+fn test_scrolling_does_not_touch_the_heap() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..BUFFER_HEIGHT {
+            writeln!(writer, "heap row {}", i).unwrap();
+        }
+        // `page_up` and `page_down` scroll from the keyboard interrupt,
+        // which may have interrupted the allocator.
+        let before = crate::allocator::stats();
+        writer.scroll_back(BUFFER_HEIGHT / 2);
+        assert_eq!(writer.scroll_offset(), BUFFER_HEIGHT / 2);
+        writer.scroll_forward(1);
+        writer.scroll_to_bottom();
+        let after = crate::allocator::stats();
+        assert_eq!(writer.scroll_offset(), 0);
+        assert_eq!(after.allocations, before.allocations);
+        assert_eq!(after.deallocations, before.deallocations);
+    });
+}
+
+#[test_case]
+fn test_hardware_cursor_follows_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_cursor(3, 7);
+        assert_eq!(cursor::location(), 3 * BUFFER_WIDTH + 7);
+        write!(writer, "ab").unwrap();
+        assert_eq!(writer.cursor(), (3, 9));
+        assert_eq!(cursor::location(), 3 * BUFFER_WIDTH + 9);
+        assert_eq!(writer.read_cell(3, 7).ascii_character, b'a');
+        // A new line above the bottom doesn't scroll.
+        let top = writer.read_row(0);
+        writeln!(writer).unwrap();
+        assert_eq!(writer.cursor(), (4, 0));
+        assert_eq!(writer.read_row(0), top);
+        writer.set_cursor(BUFFER_HEIGHT - 1, 0);
+        assert_eq!(cursor::location(), (BUFFER_HEIGHT - 1) * BUFFER_WIDTH);
+    });
+}
+
+#[test_case]
+fn test_cursor_shapes() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let shape = writer.cursor_shape();
+        writer.set_cursor_shape(CursorShape::Block);
+        assert_eq!(cursor::scanlines(), Some((0, 15)));
+        writer.set_cursor_shape(CursorShape::Hidden);
+        assert_eq!(cursor::scanlines(), None);
+        writer.set_cursor_shape(CursorShape::Underline);
+        assert_eq!(cursor::scanlines(), Some((13, 14)));
+        writer.set_cursor_shape(shape);
+    });
+}
+
+#[test_case]
+fn test_escape_sequences_set_colors() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\n\x1b[31ma\x1b[1;44mb\x1b[0;92;100mc\x1b[md").unwrap();
+        let row = BUFFER_HEIGHT - 1;
+        let color = |col| writer.read_cell(row, col).color_code;
+        assert_eq!(color(0), ColorCode::new(Color::Red, Color::Black));
+        assert_eq!(color(1), ColorCode::new(Color::LightRed, Color::Blue));
+        assert_eq!(color(2), ColorCode::new(Color::LightGreen, Color::DarkGray));
+        assert_eq!(color(3), writer.default_attributes.color_code());
+        assert_eq!(writer.read_cell(row, 3).ascii_character, b'd');
+        writeln!(writer).unwrap();
+    });
+}
+
+#[test_case]
+fn test_escape_sequences_move_and_clear() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let character = |writer: &Writer, row: usize, col: usize| writer.read_cell(row, col).ascii_character;
+        write!(writer, "\x1b[3;5Hab\x1b[2Dx\x1b7\x1b[10;1Hy\x1b8z").unwrap();
+        assert_eq!(character(&writer, 2, 4), b'x');
+        assert_eq!(character(&writer, 2, 5), b'z');
+        assert_eq!(character(&writer, 9, 0), b'y');
+        write!(writer, "\x1b[3;6H\x1b[1K").unwrap();
+        assert_eq!(character(&writer, 2, 5), b' ');
+        write!(writer, "\x1b[2J").unwrap();
+        assert_eq!(character(&writer, 9, 0), b' ');
+        writer.set_cursor(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_scroll_region_keeps_other_rows() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let character = |writer: &Writer, row: usize| writer.read_cell(row, 0).ascii_character;
+        let kept = writer.scrollback.rows.len();
+        write!(writer, "\x1b[2J\x1b[1;1Hstatus\x1b[2;4r\x1b[4;1H1\n2\n3\n").unwrap();
+        assert_eq!(character(&writer, 0), b's');
+        assert_eq!(character(&writer, 1), b'2');
+        assert_eq!(character(&writer, 2), b'3');
+        assert_eq!(character(&writer, 3), b' ');
+        assert_eq!(character(&writer, 4), b' ');
+        // Rows that scroll inside a region don't go to the scrollback.
+        assert_eq!(writer.scrollback.rows.len(), kept);
+        write!(writer, "\x1b[r").unwrap();
+        writer.set_cursor(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_writes_code_page_437_glyphs() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let row = BUFFER_HEIGHT - 1;
+        let low = cp437::LOW_GLYPHS.iter().enumerate().skip(1);
+        let high = cp437::HIGH_GLYPHS.iter().enumerate().map(|(index, &character)| (0x80 + index, character));
+        for (glyph, character) in low.map(|(index, &character)| (index, character)).chain(high) {
+            writer.set_cursor(row, 0);
+            write!(writer, "{}", character).unwrap();
+            assert_eq!(writer.read_cell(row, 0).ascii_character as usize, glyph, "{:?}", character);
+        }
+        // One replacement for each character the font doesn't have, however
+        // many bytes it takes.
+        writer.set_cursor(row, 0);
+        write!(writer, "┌€𝄞x").unwrap();
+        let written: alloc::vec::Vec<u8> = (0..4).map(|col| writer.read_cell(row, col).ascii_character).collect();
+        assert_eq!(written, [0xda, cp437::REPLACEMENT, cp437::REPLACEMENT, b'x']);
+        writeln!(writer).unwrap();
+    });
+}
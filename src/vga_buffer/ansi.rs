@@ -0,0 +1,263 @@
+// The part of VT100 (and what later terminals added to it) the screen
+// understands, so text colored for a serial terminal comes out the same on
+// the VGA:
+//
+//     ESC [ n A, B, C, D    cursor up, down, forward, back n cells
+//     ESC [ n G             cursor to column n
+//     ESC [ r ; c H or f    cursor to row r, column c
+//     ESC [ n J, K          clear the screen, the row: 0 after the cursor,
+//                           1 before it, 2 all of it
+//     ESC [ n ; ... m       colors and bold, see `Writer`
+//     ESC [ t ; b r         scroll only rows t to b
+//     ESC [ s, u or ESC 7, 8    save, restore the cursor
+//
+// Rows and columns count from 1 in the sequences, and from 0 in `Action`s.
+// The parser goes a character at a time rather than a byte at a time, so
+// characters past ASCII come out whole.
+
+const MAX_PARAMETERS: usize = 8;
+
+/// Which part of the screen or row `EraseDisplay` and `EraseLine` clear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// From the cursor to the end.
+    ToEnd,
+    /// From the start up to and including the cursor.
+    ToStart,
+    All,
+}
+
+/// The numbers in a sequence, missing ones are 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parameters {
+    values: [u16; MAX_PARAMETERS],
+    len: usize,
+}
+
+impl Parameters {
+    fn new() -> Parameters {
+        Parameters {
+            values: [0; MAX_PARAMETERS],
+            len: 0,
+        }
+    }
+
+    fn add_digit(&mut self, digit: u8) {
+        if let Some(value) = self.values.get_mut(self.len) {
+            *value = value.saturating_mul(10).saturating_add(digit as u16);
+        }
+    }
+
+    // Moves on to the next number, past the last one any more are dropped.
+    fn next(&mut self) {
+        self.len = (self.len + 1).min(MAX_PARAMETERS);
+    }
+
+    /// The `index`th number, or `default` if it's missing or 0.
+    pub fn get(&self, index: usize, default: u16) -> u16 {
+        match self.values[..self.len].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    /// All of the numbers. There's at least one, `ESC [ m` has a 0.
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+}
+
+/// What the screen should do about the characters so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// A character that isn't part of a sequence, control characters
+    /// included.
+    Print(char),
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBack(usize),
+    CursorTo { row: usize, col: usize },
+    CursorToColumn(usize),
+    EraseDisplay(Erase),
+    EraseLine(Erase),
+    SaveCursor,
+    RestoreCursor,
+    /// Rows `top` to `bottom` scroll, the rest stay put. No `bottom` means
+    /// the bottom of the screen.
+    SetScrollRegion { top: usize, bottom: Option<usize> },
+    SelectGraphicRendition(Parameters),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape, // got ESC
+    Csi, // got ESC [ and maybe some of the numbers
+    Ignore, // in a sequence we don't know, waiting for its last character
+}
+
+/// Turns characters into `Action`s, one at a time, so a sequence can be
+/// split across writes.
+#[derive(Debug, Clone)]
+pub struct Parser {
+    state: State,
+    parameters: Parameters,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            parameters: Parameters::new(),
+        }
+    }
+
+    pub fn advance(&mut self, character: char) -> Option<Action> {
+        match (self.state, character) {
+            // ESC starts over even in the middle of a sequence, CAN and SUB
+            // call the sequence off.
+            (_, '\x1b') => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, character) => Some(Action::Print(character)),
+            (_, '\x18') | (_, '\x1a') => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.parameters = Parameters::new();
+                None
+            }
+            (State::Escape, character) => {
+                self.state = State::Ground;
+                match character {
+                    '7' => Some(Action::SaveCursor),
+                    '8' => Some(Action::RestoreCursor),
+                    _ => None,
+                }
+            }
+            (State::Csi, '0'..='9') => {
+                self.parameters.add_digit(character as u8 - b'0');
+                None
+            }
+            (State::Csi, ';') => {
+                self.parameters.next();
+                None
+            }
+            (State::Csi, '@'..='~') => {
+                self.state = State::Ground;
+                self.parameters.next();
+                self.dispatch(character)
+            }
+            // Private (`ESC [ ?`) and intermediate characters, which we don't do.
+            (State::Csi, _) => {
+                self.state = State::Ignore;
+                None
+            }
+            (State::Ignore, '@'..='~') => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Ignore, _) => None,
+        }
+    }
+
+    // Works out what a whole `ESC [` sequence does from its last character.
+    fn dispatch(&self, last: char) -> Option<Action> {
+        let parameters = &self.parameters;
+        let count = || parameters.get(0, 1) as usize;
+        let erase = || match parameters.get(0, 0) {
+            0 => Some(Erase::ToEnd),
+            1 => Some(Erase::ToStart),
+            2 | 3 => Some(Erase::All),
+            _ => None,
+        };
+        match last {
+            'A' => Some(Action::CursorUp(count())),
+            'B' => Some(Action::CursorDown(count())),
+            'C' => Some(Action::CursorForward(count())),
+            'D' => Some(Action::CursorBack(count())),
+            'G' => Some(Action::CursorToColumn(count() - 1)),
+            'H' | 'f' => Some(Action::CursorTo {
+                row: parameters.get(0, 1) as usize - 1,
+                col: parameters.get(1, 1) as usize - 1,
+            }),
+            'J' => erase().map(Action::EraseDisplay),
+            'K' => erase().map(Action::EraseLine),
+            'm' => Some(Action::SelectGraphicRendition(*parameters)),
+            'r' => Some(Action::SetScrollRegion {
+                top: parameters.get(0, 1) as usize - 1,
+                bottom: match parameters.get(1, 0) {
+                    0 => None,
+                    bottom => Some(bottom as usize - 1),
+                },
+            }),
+            's' => Some(Action::SaveCursor),
+            'u' => Some(Action::RestoreCursor),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+fn parse(s: &str) -> alloc::vec::Vec<Action> {
+    let mut parser = Parser::new();
+    s.chars().filter_map(|character| parser.advance(character)).collect()
+}
+
+#[test_case]
+fn test_parses_cursor_sequences() {
+    assert_eq!(
+        parse("a\x1b[A\x1b[12;5H\x1b[;7f\x1b[3D"),
+        [
+            Action::Print('a'),
+            Action::CursorUp(1),
+            Action::CursorTo { row: 11, col: 4 },
+            Action::CursorTo { row: 0, col: 6 },
+            Action::CursorBack(3),
+        ]
+    );
+    assert_eq!(parse("\x1b7\x1b[s\x1b8\x1b[u"), [
+        Action::SaveCursor,
+        Action::SaveCursor,
+        Action::RestoreCursor,
+        Action::RestoreCursor,
+    ]);
+    assert_eq!(parse("\x1b[2J\x1b[K\x1b[1K"), [
+        Action::EraseDisplay(Erase::All),
+        Action::EraseLine(Erase::ToEnd),
+        Action::EraseLine(Erase::ToStart),
+    ]);
+    assert_eq!(parse("\x1b[5;20r\x1b[r"), [
+        Action::SetScrollRegion { top: 4, bottom: Some(19) },
+        Action::SetScrollRegion { top: 0, bottom: None },
+    ]);
+}
+
+#[test_case]
+fn test_parses_graphic_rendition() {
+    let numbers = |action: &Action| match action {
+        Action::SelectGraphicRendition(parameters) => parameters.iter().collect::<alloc::vec::Vec<_>>(),
+        _ => panic!("not SGR: {:?}", action),
+    };
+    assert_eq!(numbers(&parse("\x1b[m")[0]), [0]);
+    assert_eq!(numbers(&parse("\x1b[1;31;104m")[0]), [1, 31, 104]);
+    // Past `MAX_PARAMETERS` they're dropped.
+    assert_eq!(numbers(&parse("\x1b[1;2;3;4;5;6;7;8;9;10m")[0]), [1, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[test_case]
+fn test_skips_unknown_and_broken_sequences() {
+    // A private sequence, an unknown one, one called off with CAN, one
+    // cut short by another ESC, and one with a letter past ASCII in it.
+    assert_eq!(parse("\x1b[?25lx\x1b[5zy\x1b[3\x18z\x1b[4\x1b[B\x1b[1éAü"), [
+        Action::Print('x'),
+        Action::Print('y'),
+        Action::Print('z'),
+        Action::CursorDown(1),
+        Action::Print('ü'),
+    ]);
+}
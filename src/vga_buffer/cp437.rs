@@ -0,0 +1,81 @@
+// The VGA's font is code page 437: ASCII in the middle, with smileys, card
+// suits and arrows below it and accented letters, box drawing, Greek and
+// math symbols above it. Characters are looked up by what they look like.
+
+/// Shown for characters the font doesn't have.
+pub const REPLACEMENT: u8 = 0xfe; // ■
+
+/// The characters the glyphs 0x00 to 0x1f stand for. 0x00 is a blank cell,
+/// there's no character that should turn into it.
+pub const LOW_GLYPHS: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The character glyph 0x7f stands for, in place of DEL.
+pub const HOUSE: char = '⌂';
+
+/// The characters the glyphs 0x80 to 0xff stand for.
+pub const HIGH_GLYPHS: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+// Characters that aren't in the font, but look close enough to one that is.
+const LOOKALIKES: [(char, u8); 6] = [
+    ('\u{3b2}', 0xe1), // β, drawn as ß
+    ('\u{3bc}', 0xe6), // μ, drawn as the micro sign
+    ('\u{2211}', 0xe4), // ∑, drawn as Σ
+    ('\u{2126}', 0xea), // the ohm sign, drawn as Ω
+    ('\u{2208}', 0xee), // ∈, drawn as ε
+    ('\u{3d5}', 0xed), // ϕ, drawn as φ
+];
+
+/// The glyph for `character`, if the font has one. Control characters have
+/// none, the glyphs in their place are for the symbols in `LOW_GLYPHS`.
+pub fn glyph(character: char) -> Option<u8> {
+    match character {
+        ' '..='~' => Some(character as u8),
+        HOUSE => Some(0x7f),
+        '\0'..='\x7f' => None,
+        _ => {
+            if let Some(index) = LOW_GLYPHS.iter().position(|&glyph| glyph == character) {
+                return Some(index as u8);
+            }
+            if let Some(index) = HIGH_GLYPHS.iter().position(|&glyph| glyph == character) {
+                return Some(0x80 + index as u8);
+            }
+            LOOKALIKES
+                .iter()
+                .find(|&&(lookalike, _)| lookalike == character)
+                .map(|&(_, glyph)| glyph)
+        }
+    }
+}
+
+/// The glyph for `character`, or `REPLACEMENT` if the font has none.
+pub fn glyph_or_replacement(character: char) -> u8 {
+    glyph(character).unwrap_or(REPLACEMENT)
+}
+
+#[test_case]
+fn test_glyphs() {
+    assert_eq!(glyph('A'), Some(b'A'));
+    assert_eq!(glyph('\n'), None);
+    assert_eq!(glyph('\0'), None);
+    assert_eq!(glyph('☺'), Some(0x01));
+    assert_eq!(glyph('⌂'), Some(0x7f));
+    assert_eq!(glyph('é'), Some(0x82));
+    assert_eq!(glyph('╬'), Some(0xce));
+    assert_eq!(glyph('π'), Some(0xe3));
+    assert_eq!(glyph('\u{a0}'), Some(0xff));
+    assert_eq!(glyph('\u{3bc}'), glyph('\u{b5}'));
+    assert_eq!(glyph('€'), None);
+    assert_eq!(glyph_or_replacement('€'), REPLACEMENT);
+}
@@ -0,0 +1,85 @@
+use x86_64::instructions::port::Port;
+
+// The blinking cursor is drawn by the VGA's CRT controller, not by us. Its
+// registers sit behind an index port and a data port: write a register's
+// number to 0x3d4, then read or write its value at 0x3d5.
+
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+
+const CURSOR_START: u8 = 0x0a; // first scanline of the cursor, and bit 5 hides it
+const CURSOR_END: u8 = 0x0b; // last scanline of the cursor
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+const CURSOR_DISABLE: u8 = 1 << 5;
+const SCANLINE_MASK: u8 = 0x1f;
+
+// Characters are 16 scanlines tall in 80x25 text mode.
+const LAST_SCANLINE: u8 = 15;
+
+/// What the hardware cursor looks like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    /// Two scanlines near the bottom of the cell, like the BIOS sets it up.
+    Underline,
+    /// The whole cell.
+    Block,
+    Hidden,
+}
+
+fn read_register(index: u8) -> u8 {
+    unsafe {
+        Port::new(CRTC_INDEX).write(index);
+        Port::new(CRTC_DATA).read()
+    }
+}
+
+fn write_register(index: u8, value: u8) {
+    unsafe {
+        Port::new(CRTC_INDEX).write(index);
+        Port::new(CRTC_DATA).write(value);
+    }
+}
+
+/// Puts the cursor on the cell at `row` and `col`.
+pub(super) fn move_to(row: usize, col: usize) {
+    let location = (row * super::BUFFER_WIDTH + col) as u16;
+    write_register(CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+    write_register(CURSOR_LOCATION_LOW, location as u8);
+}
+
+/// The cell the cursor is on, counting along the rows from the top left.
+#[cfg(test)]
+pub(super) fn location() -> usize {
+    let high = read_register(CURSOR_LOCATION_HIGH) as usize;
+    let low = read_register(CURSOR_LOCATION_LOW) as usize;
+    high << 8 | low
+}
+
+pub(super) fn set_shape(shape: CursorShape) {
+    // The registers' other bits do other things, so only the cursor's ones
+    // are changed.
+    let start = read_register(CURSOR_START) & !(CURSOR_DISABLE | SCANLINE_MASK);
+    let end = read_register(CURSOR_END) & !SCANLINE_MASK;
+    let (first, last) = match shape {
+        CursorShape::Underline => (LAST_SCANLINE - 2, LAST_SCANLINE - 1),
+        CursorShape::Block => (0, LAST_SCANLINE),
+        CursorShape::Hidden => {
+            write_register(CURSOR_START, start | CURSOR_DISABLE);
+            return;
+        }
+    };
+    write_register(CURSOR_START, start | first);
+    write_register(CURSOR_END, end | last);
+}
+
+/// Whether the cursor is shown, and the scanlines it covers if so.
+#[cfg(test)]
+pub(super) fn scanlines() -> Option<(u8, u8)> {
+    let start = read_register(CURSOR_START);
+    if start & CURSOR_DISABLE != 0 {
+        return None;
+    }
+    Some((start & SCANLINE_MASK, read_register(CURSOR_END) & SCANLINE_MASK))
+}
@@ -0,0 +1,168 @@
+use super::{Color, ColorCode, Screen, ScreenChar, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Several writers share the screen, with one of them shown at a time. The
+// one shown writes straight to the VGA, the others to memory of their own,
+// and switching swaps what's on the screen for what the new one has kept.
+//
+// Console 0 is `WRITER`, which `print!` writes to, so it has the kernel log.
+
+/// How many virtual consoles there are, one for each of Alt+F1 to Alt+F6.
+pub const CONSOLE_COUNT: usize = 6;
+
+static SHOWN: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    // Consoles 1 and up. They live on the heap, so they can't be used
+    // before it's set up.
+    static ref OTHER_CONSOLES: Vec<Mutex<Writer>> = (1..CONSOLE_COUNT).map(|_| Mutex::new(off_screen_writer())).collect();
+}
+
+fn off_screen_writer() -> Writer {
+    let blank = ScreenChar {
+        ascii_character: b' ',
+        color_code: ColorCode::new(Color::LightGray, Color::Black),
+    };
+    let screen = Screen::OffScreen(Box::new([[blank; BUFFER_WIDTH]; BUFFER_HEIGHT]));
+    Writer::new(Color::LightGray, Color::Black, screen)
+}
+
+/// One of the virtual consoles, as something to `write!` to. Each write
+/// takes the console's lock with interrupts off, like `print!` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Console(usize);
+
+impl Console {
+    /// Console `index`, counting from 0, if there is one.
+    pub fn new(index: usize) -> Option<Console> {
+        if index < CONSOLE_COUNT {
+            Some(Console(index))
+        } else {
+            None
+        }
+    }
+
+    /// The console `print!` writes to.
+    pub fn log() -> Console {
+        Console(0)
+    }
+
+    /// The console on the screen.
+    pub fn shown() -> Console {
+        Console(SHOWN.load(Ordering::Relaxed))
+    }
+
+    pub fn all() -> impl Iterator<Item = Console> {
+        (0..CONSOLE_COUNT).map(Console)
+    }
+
+    pub fn index(self) -> usize {
+        self.0
+    }
+
+    /// The console's writer, for more than writing text. Lock it with
+    /// interrupts off, like `WRITER`.
+    pub fn writer(self) -> &'static Mutex<Writer> {
+        match self.0 {
+            0 => &WRITER,
+            index => &OTHER_CONSOLES[index - 1],
+        }
+    }
+
+    pub fn is_shown(self) -> bool {
+        self == Console::shown()
+    }
+
+    /// Puts this console on the screen, in place of the one there now.
+    pub fn show(self) {
+        interrupts::without_interrupts(|| {
+            let shown = Console::shown();
+            if shown == self {
+                return;
+            }
+            let mut old = shown.writer().lock();
+            let mut new = self.writer().lock();
+            switch(&mut old, &mut new);
+            SHOWN.store(self.0, Ordering::Relaxed);
+        });
+    }
+
+    /// Like `show`, but gives up rather than wait when either console is
+    /// locked, and says whether it's shown now. For panics and exceptions,
+    /// where the code that was interrupted may hold the lock.
+    pub fn try_show(self) -> bool {
+        interrupts::without_interrupts(|| {
+            let shown = Console::shown();
+            if shown == self {
+                return true;
+            }
+            match (shown.writer().try_lock(), self.writer().try_lock()) {
+                (Some(mut old), Some(mut new)) => {
+                    switch(&mut old, &mut new);
+                    SHOWN.store(self.0, Ordering::Relaxed);
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        interrupts::without_interrupts(|| self.writer().lock().write_str(s))
+    }
+}
+
+// Swaps what's on the VGA for what `new` kept off screen, then the screens
+// themselves, so `new` writes to the VGA and `old` to memory, each finding
+// its own characters there.
+fn switch(old: &mut Writer, new: &mut Writer) {
+    // The mouse cursor belongs to the screen, not to a console.
+    let mouse_cursor = old.mouse_cursor();
+    old.set_mouse_cursor(None);
+    for row in 0..BUFFER_HEIGHT {
+        let shown = old.read_row(row);
+        let kept = new.read_row(row);
+        old.write_row(row, &kept);
+        new.write_row(row, &shown);
+    }
+    core::mem::swap(&mut old.screen, &mut new.screen);
+    new.show_cursor();
+    new.set_mouse_cursor(mouse_cursor);
+}
+
+#[test_case]
+fn test_consoles_keep_their_own_text() {
+    use core::fmt::Write;
+    let log = Console::log();
+    let other = Console::new(3).unwrap();
+    assert_eq!(Console::new(CONSOLE_COUNT), None);
+    write!(Console::new(3).unwrap(), "\x1b[1;1Hon three").unwrap();
+    let character = |console: Console, row: usize, col: usize| {
+        interrupts::without_interrupts(|| console.writer().lock().read_cell(row, col).ascii_character)
+    };
+    let log_corner = character(log, 0, 0);
+    assert_eq!(character(other, 0, 3), b't');
+    assert!(!other.is_shown());
+
+    other.show();
+    assert!(other.is_shown() && !log.is_shown());
+    assert_eq!(character(other, 0, 3), b't');
+    assert_eq!(character(log, 0, 0), log_corner);
+    // Printing still goes to the log, and stays off the screen.
+    crate::println!("while console 3 is shown");
+    assert_eq!(character(other, 0, 3), b't');
+    assert_eq!(character(log, BUFFER_HEIGHT - 2, 0), b'w');
+
+    assert!(log.try_show());
+    assert!(log.is_shown());
+    assert_eq!(character(log, BUFFER_HEIGHT - 2, 0), b'w');
+    assert_eq!(character(other, 0, 3), b't');
+}
@@ -0,0 +1,127 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::gdt;
+use kurogane_os::memory::paging::kernel_page_table;
+use kurogane_os::{serial_print, ExpectedException};
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("alignment_check::alignment_check_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+// The CPU only checks alignment at CPL 3, so the misaligned access has to
+// run in ring 3, from a page of its own with the stack at the other end.
+const MISALIGNED_LOAD: &[u8] = &[0x8b, 0x44, 0x24, 0x01]; // mov eax, [rsp + 1]
+const UD2: &[u8] = &[0x0f, 0x0b]; // in case the load doesn't fault after all
+// In a P4 entry nothing else uses, so every table on the way to it can be
+// made user accessible.
+const USER_PAGE: u64 = 0x_7000_0000_0000;
+const USER_STACK_TOP: u64 = USER_PAGE + 4096 - 64; // the load stays inside the page
+
+const RFLAGS_RESERVED: u64 = 1 << 1; // always set
+const RFLAGS_ALIGNMENT_CHECK: u64 = 1 << 18;
+
+#[inline(never)]
+fn trigger() {
+    let (user_code, user_data) = load_gdt_with_ring_3();
+    let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    kernel_page_table()
+        .map_range(Page::range(page, page + 1), flags)
+        .expect("mapping the ring 3 page failed");
+    unsafe {
+        let code = USER_PAGE as *mut u8;
+        core::ptr::copy_nonoverlapping(MISALIGNED_LOAD.as_ptr(), code, MISALIGNED_LOAD.len());
+        core::ptr::copy_nonoverlapping(UD2.as_ptr(), code.add(MISALIGNED_LOAD.len()), UD2.len());
+        // #AC needs CR0.AM as well as RFLAGS.AC.
+        Cr0::update(|flags| flags.insert(Cr0Flags::ALIGNMENT_MASK));
+        // Return into ring 3 at the code, with RFLAGS.AC set and interrupts off.
+        asm!(
+            "push {data}", // SS
+            "push {stack}", // RSP
+            "push {rflags}",
+            "push {code}", // CS
+            "push {rip}",
+            "iretq",
+            data = in(reg) u64::from(user_data.0 | 3),
+            stack = in(reg) USER_STACK_TOP,
+            rflags = in(reg) RFLAGS_RESERVED | RFLAGS_ALIGNMENT_CHECK,
+            code = in(reg) u64::from(user_code.0 | 3),
+            rip = in(reg) USER_PAGE,
+            options(noreturn),
+        );
+    }
+}
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<(GlobalDescriptorTable, [SegmentSelector; 4])> = Once::new();
+// Where the CPU switches to when the exception takes it back to ring 0.
+static mut RING_0_STACK: [u8; 16 * 4096] = [0; 16 * 4096];
+
+// The kernel's GDT has no ring 3 segments, and its TSS no stack for
+// interrupts that come from ring 3, so this loads tables that have both,
+// keeping the kernel's interrupt stacks. Returns the ring 3 code and data
+// selectors.
+fn load_gdt_with_ring_3() -> (SegmentSelector, SegmentSelector) {
+    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::tables::load_tss;
+
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        let stack = unsafe { &RING_0_STACK };
+        tss.privilege_stack_table[0] = VirtAddr::from_ptr(stack) + stack.len() as u64;
+        for (index, entry) in tss.interrupt_stack_table.iter_mut().enumerate() {
+            if let Some(stack) = gdt::ist_stack(index as u16) {
+                *entry = stack.top;
+            }
+        }
+        tss
+    });
+    let (gdt, [kernel_code, user_code, user_data, tss_selector]) = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        (gdt, [kernel_code, user_code, user_data, tss_selector])
+    });
+    gdt.load();
+    unsafe {
+        set_cs(*kernel_code);
+        load_tss(*tss_selector);
+    }
+    (*user_code, *user_data)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::AlignmentCheck,
+            header: "EXCEPTION: ALIGNMENT CHECK (#AC, vector 17)",
+            instruction_pointer: USER_PAGE..USER_PAGE + 1,
+            instruction: Some(MISALIGNED_LOAD),
+            error_code: Some(0), // #AC always has a zero error code
+            detail: |detail| detail == ExceptionDetail::None,
+            report_contains: &[],
+        },
+    )
+}
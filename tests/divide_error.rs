@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("divide_error::divide_error_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+#[inline(never)]
+fn trigger() {
+    // Rust checks division by zero before dividing, so go around it.
+    unsafe {
+        asm!(
+            "div {0}",
+            in(reg) 0u64,
+            inout("rax") 1u64 => _,
+            inout("rdx") 0u64 => _,
+            options(nomem, nostack),
+        );
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::DivideError,
+            header: "EXCEPTION: DIVIDE ERROR (#DE, vector 0)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: None,
+            detail: |detail| detail == ExceptionDetail::None,
+            report_contains: &[],
+        },
+    )
+}
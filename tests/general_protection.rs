@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail, SelectorErrorCode};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("general_protection::general_protection_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+const BAD_SELECTOR: u16 = 0x1230; // GDT index 582, far past the end of our GDT
+
+#[inline(never)]
+fn trigger() {
+    // Loading a selector beyond the GDT limit raises #GP with the selector
+    // as the error code.
+    unsafe { asm!("mov ds, {0:x}", in(reg) BAD_SELECTOR, options(nomem, nostack)) };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::GeneralProtectionFault,
+            header: "EXCEPTION: GENERAL PROTECTION FAULT (#GP, vector 13)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: Some(BAD_SELECTOR as u64),
+            detail: |detail| detail == ExceptionDetail::Selector(SelectorErrorCode(BAD_SELECTOR as u64)),
+            report_contains: &["selector:            GDT index 582 (selector 0x1230)"],
+        },
+    )
+}
@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kurogane_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::allocator::HEAP_SIZE;
+use kurogane_os::serial_println;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kurogane_os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// `kurogane_os::init` leaves allocations behind for good, like the I/O
+// APICs and the consoles' scrollback, so the bump allocator never gets back
+// to an empty heap, and runs out here too.
+#[cfg(not(feature = "bump-allocator"))]
+#[test_case]
+fn many_boxes() {
+    // Allocates more than the whole heap in total, which only works if
+    // freed memory gets reused.
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+// The bump allocator only reuses memory once every allocation is freed,
+// so this one is expected to run it out of memory.
+#[cfg(not(feature = "bump-allocator"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    // Same as above, but with one allocation outliving all the others,
+    // so the allocator can't just start over from an empty heap.
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn mixed_sizes() {
+    let mut boxes: Vec<Vec<u8>> = Vec::new();
+    for round in 0..64 {
+        for size in [1, 7, 64, 300, 2048].iter() {
+            boxes.push(alloc::vec![round as u8; *size]);
+        }
+        // Free every other allocation to leave holes behind.
+        if round % 2 == 1 {
+            boxes.retain(|b| b.len() % 2 == 0);
+        }
+    }
+    for b in boxes.iter() {
+        assert!(b.iter().all(|byte| *byte == b[0]));
+    }
+}
+
+#[test_case]
+fn btree_map() {
+    let mut map = BTreeMap::new();
+    for i in 0..500u32 {
+        map.insert(i, i * 2);
+    }
+    for i in (0..500u32).step_by(3) {
+        map.remove(&i);
+    }
+    assert_eq!(map.get(&1), Some(&2));
+    assert_eq!(map.get(&3), None);
+}
+
+#[test_case]
+fn string_growth() {
+    let mut s = String::new();
+    for _ in 0..1000 {
+        s.push_str("kurogane");
+    }
+    assert_eq!(s.len(), 8000);
+}
+
+#[test_case]
+fn fragmentation_report() {
+    // Runs last, so the report shows the heap after everything above.
+    let stats = kurogane_os::allocator::stats();
+    assert_eq!(stats.heap_size, HEAP_SIZE);
+    assert!(stats.free.free_bytes <= stats.heap_size);
+    serial_println!("");
+    kurogane_os::allocator::print_fragmentation_report();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::test_panic_handler(info)
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kurogane_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kurogane_os::time::{self, hpet, ClockSource};
+use kurogane_os::{interrupts, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kurogane_os::init(boot_info);
+    if hpet::hpet().is_none() {
+        // Nothing to test, QEMU was started with `-no-hpet`.
+        serial_println!("no HPET, skipping");
+        kurogane_os::exit_qemu(kurogane_os::QemuExitCode::Success);
+    } else {
+        test_main();
+    }
+    loop {}
+}
+
+#[test_case]
+fn ticks_continue_on_the_hpet() {
+    time::set_clock_source(ClockSource::Hpet).expect("can't switch to the HPET");
+    assert_eq!(time::clock_source(), ClockSource::Hpet);
+    assert_eq!(time::tick_frequency(), time::DEFAULT_TICK_HZ);
+    let before = time::monotonic_nanos();
+    time::sleep_ticks(20);
+    let elapsed = time::monotonic_nanos() - before;
+    time::set_clock_source(ClockSource::Pit).unwrap();
+    // 20 ticks at 1000 Hz, give or take one.
+    assert!(elapsed >= 19_000_000 && elapsed <= 21_000_000, "{} ns for 20 ticks", elapsed);
+}
+
+#[test_case]
+fn monotonic_nanos_survive_switching_sources() {
+    let mut last = time::monotonic_nanos();
+    for &source in [ClockSource::Hpet, ClockSource::Pit, ClockSource::Hpet, ClockSource::Pit].iter() {
+        time::set_clock_source(source).unwrap();
+        time::sleep_ticks(2);
+        let now = time::monotonic_nanos();
+        assert!(now > last, "time went from {} ns back to {} ns", last, now);
+        last = now;
+    }
+}
+
+static ONE_SHOTS: AtomicU64 = AtomicU64::new(0);
+
+fn one_shot(_line: u8) {
+    ONE_SHOTS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn timer_1_fires_once_on_irq_8() {
+    let hpet = hpet::hpet().unwrap();
+    time::set_clock_source(ClockSource::Hpet).unwrap();
+    interrupts::register_irq(8, one_shot).expect("IRQ 8 is taken");
+    hpet.set_one_shot(1, 2_000_000).unwrap();
+    time::sleep_ticks(10);
+    interrupts::unregister_irq(8);
+    hpet.disable_timer(1);
+    time::set_clock_source(ClockSource::Pit).unwrap();
+    assert_eq!(ONE_SHOTS.load(Ordering::SeqCst), 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::test_panic_handler(info)
+}
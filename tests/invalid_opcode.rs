@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("invalid_opcode::invalid_opcode_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+#[inline(never)]
+fn trigger() {
+    // ud2 is guaranteed to be undefined on every x86 CPU.
+    unsafe { asm!("ud2", options(nomem, nostack)) };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::InvalidOpcode,
+            header: "EXCEPTION: INVALID OPCODE (#UD, vector 6)",
+            instruction_pointer: code_of(trigger),
+            instruction: Some(&[0x0f, 0x0b]), // the ud2
+            error_code: None,
+            detail: |detail| detail == ExceptionDetail::None,
+            report_contains: &[],
+        },
+    )
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("machine_check_wiring::machine_check_handler_is_wired...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+#[inline(never)]
+fn trigger() {
+    // A real machine check needs failing hardware, so this only checks
+    // that vector 18 reaches the handler and that it reads MCG_STATUS: #MC
+    // has no error code, so a software interrupt gets there the same way.
+    unsafe { asm!("int 0x12", options(nomem, nostack)) };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::MachineCheck,
+            header: "EXCEPTION: MACHINE CHECK (#MC, vector 18)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: None,
+            detail: |detail| match detail {
+                // Nothing is actually wrong with the hardware, so no machine
+                // check is in progress.
+                ExceptionDetail::MachineCheck { mcg_status } => {
+                    mcg_status.map_or(true, |status| status & 0b100 == 0)
+                }
+                _ => false,
+            },
+            report_contains: &["mcg status:"],
+        },
+    )
+}
@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("page_fault::page_fault_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+// Nothing is ever mapped down here, well away from the kernel, heap and
+// physical memory mapping.
+const UNMAPPED: u64 = 0xdead_beaf_000;
+
+#[inline(never)]
+fn trigger() {
+    unsafe { (UNMAPPED as *mut u64).write_volatile(42) };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::PageFault,
+            header: "EXCEPTION: PAGE FAULT (#PF, vector 14)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: Some(PageFaultErrorCode::CAUSED_BY_WRITE.bits()),
+            detail: |detail| {
+                // A kernel mode write to a non-present page.
+                detail
+                    == ExceptionDetail::PageFault {
+                        accessed_address: VirtAddr::new(UNMAPPED),
+                        error_code: PageFaultErrorCode::CAUSED_BY_WRITE,
+                    }
+            },
+            report_contains: &[
+                "accessed address:    0xdeadbeaf000",
+                "cause:               write to a non-present page in kernel mode",
+            ],
+        },
+    )
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(asm)]
+#![test_runner(kurogane_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kurogane_os::interrupts::{self, InterruptController};
+use kurogane_os::time::{self, rtc};
+
+// QEMU always has an APIC, so this kernel asks for the 8259 PICs instead,
+// for the machines that don't.
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kurogane_os::cmdline::set("irqchip=pic");
+    kurogane_os::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn the_pics_are_in_charge() {
+    assert_eq!(interrupts::interrupt_controller(), InterruptController::Pic);
+    assert!(!kurogane_os::apic::is_enabled());
+}
+
+#[test_case]
+fn the_timer_ticks() {
+    let before = time::ticks();
+    time::sleep_ticks(10);
+    assert!(time::ticks() >= before + 10);
+}
+
+static TEST_IRQS: AtomicU64 = AtomicU64::new(0);
+
+fn test_irq_handler(line: u8) {
+    assert_eq!(line, 10);
+    TEST_IRQS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn irqs_are_dispatched() {
+    interrupts::register_irq(10, test_irq_handler).unwrap();
+    unsafe { asm!("int 0x2a", options(nomem, nostack)) }; // vector 32 + 10
+    interrupts::unregister_irq(10);
+    assert_eq!(TEST_IRQS.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn spurious_irqs_are_counted() {
+    // Raised in software, so the PICs have nothing in service and both look
+    // exactly like spurious interrupts.
+    let irq7 = interrupts::spurious_irq_count(7);
+    let irq15 = interrupts::spurious_irq_count(15);
+    unsafe { asm!("int 0x27", "int 0x2f", options(nomem, nostack)) };
+    assert_eq!(interrupts::spurious_irq_count(7), irq7 + 1);
+    assert_eq!(interrupts::spurious_irq_count(15), irq15 + 1);
+    // The timer still gets through, so the master wasn't left waiting for
+    // an EOI.
+    let before = time::ticks();
+    time::sleep_ticks(2);
+    assert!(time::ticks() >= before + 2);
+}
+
+#[test_case]
+fn the_rtc_interrupts_through_the_slave() {
+    // IRQ 8 is on the slave PIC, which only raises it again once it has had
+    // its EOI, so many interrupts show the dispatcher sends one.
+    let before = rtc::periodic_interrupt_count();
+    rtc::enable_periodic_interrupt(6).expect("IRQ 8 is taken"); // 1024 Hz
+    time::sleep_ticks(20);
+    rtc::disable_periodic_interrupt();
+    let count = rtc::periodic_interrupt_count() - before;
+    assert!(count >= 10, "only {} RTC interrupts in 20 ms", count);
+}
+
+#[test_case]
+fn the_rtc_reads_a_plausible_date() {
+    let now = rtc::read();
+    assert!(now.year >= 2020);
+    assert!(now.month >= 1 && now.month <= 12);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::test_panic_handler(info)
+}
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{DescriptorTable, Exception, ExceptionDetail, SelectorErrorCode};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("segment_not_present::segment_not_present_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+#[inline(never)]
+fn trigger() {
+    // Nothing is installed for vector 0x80, and interrupting through a gate
+    // that is not present raises #NP naming the IDT entry.
+    unsafe { asm!("int 0x80", options(nomem, nostack)) };
+}
+
+// The error code names IDT entry 0x80.
+const SELECTOR: SelectorErrorCode = SelectorErrorCode((0x80 << 3) | 0b010);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::SegmentNotPresent,
+            header: "EXCEPTION: SEGMENT NOT PRESENT (#NP, vector 11)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: Some(SELECTOR.0),
+            detail: |detail| {
+                detail == ExceptionDetail::Selector(SELECTOR) && SELECTOR.table() == DescriptorTable::Idt
+            },
+            report_contains: &["selector:            IDT vector 128"],
+        },
+    )
+}
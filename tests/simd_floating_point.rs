@@ -0,0 +1,66 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("simd_floating_point::simd_floating_point_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+const MXCSR_UNMASK_DIVIDE_BY_ZERO: u32 = 0x1f80 & !(1 << 9);
+
+#[inline(never)]
+fn trigger() {
+    // The kernel itself is built without SSE, so switch it on (CR0.EM off,
+    // CR0.MP, CR4.OSFXSR and CR4.OSXMMEXCPT on), unmask divide-by-zero in
+    // MXCSR and divide 1.0 by 0.0.
+    unsafe {
+        asm!(
+            "mov rax, cr0",
+            "and rax, -5",
+            "or rax, 0x2",
+            "mov cr0, rax",
+            "mov rax, cr4",
+            "or rax, 0x600",
+            "mov cr4, rax",
+            "ldmxcsr [{0}]",
+            "xorps xmm0, xmm0",
+            "mov eax, 0x3f800000",
+            "movd xmm1, eax",
+            "divss xmm1, xmm0",
+            in(reg) &MXCSR_UNMASK_DIVIDE_BY_ZERO,
+            out("rax") _,
+            options(nostack),
+        );
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::SimdFloatingPoint,
+            header: "EXCEPTION: SIMD FLOATING-POINT EXCEPTION (#XM, vector 19)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: None,
+            detail: |detail| match detail {
+                ExceptionDetail::Simd { mxcsr } => mxcsr & 0b100 != 0, // divide-by-zero
+                _ => false,
+            },
+            report_contains: &["mxcsr:", "(divide-by-zero)"],
+        },
+    )
+}
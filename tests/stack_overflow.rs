@@ -3,19 +3,55 @@
 #![no_main]
 use kurogane_os::serial_print;
 use kurogane_os::{exit_qemu, QemuExitCode, serial_println};
-use x86_64::structures::idt::InterruptStackFrame;
+use kurogane_os::gdt::{self, DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptDescriptorTable;
 
+// This runs in two stages:
+//   1. The kernel stack overflows into the bootloader's guard page. With no
+//      page fault handler in TEST_IDT that turns into a double fault, whose
+//      handler runs on the double fault IST stack.
+//   2. The double fault handler loads GUARD_IDT and overflows its own IST
+//      stack. That has to hit the guard page below it, which raises a page
+//      fault on the page fault IST stack, where we check the faulting address.
+
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: &mut InterruptStackFrame,
     _error_code: u64
 ) -> ! {
     serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+
+    serial_print!("stack_overflow::ist_stack_overflow...\t");
+    GUARD_IDT.load();
+    stack_overflow();
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: execution continued after the IST stack overflow\n");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    _error_code: PageFaultErrorCode
+) {
+    use x86_64::registers::control::Cr2;
+
+    let double_fault_stack = gdt::ist_stack(DOUBLE_FAULT_IST_INDEX).expect("no double fault stack");
+    if double_fault_stack.guard_page_contains(Cr2::read()) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: page fault at {:#x}, outside of the guard page\n", Cr2::read().as_u64());
+        exit_qemu(QemuExitCode::Failed);
+    }
     loop {}
 }
+
 lazy_static! {
     // A static item is similar to a constant, but it represents a precise
     // location in memory, and has a lifetime that outlives all other lifetimes.
@@ -31,17 +67,34 @@ lazy_static! {
                 // Access fields of unions
             idt.double_fault
                 .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(kurogane_os::gdt::DOUBLE_FAULT_IST_INDEX);
-            // 
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            //
+        }
+        idt
+    };
+
+    // Only used once we're on the double fault stack, a page fault now means
+    // that stack overflowed.
+    static ref GUARD_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(PAGE_FAULT_IST_INDEX);
         }
         idt
     };
 }
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...\t");
 
-    kurogane_os::gdt::init();
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::kernel_stack_overflow...\t");
+
+    // The IST stacks are mapped by `gdt::init`, which needs the page tables
+    // and the frame allocator.
+    kurogane_os::memory::init(boot_info);
+    gdt::init();
     init_test_idt();
 
     stack_overflow();
@@ -66,4 +119,4 @@ fn stack_overflow() {
 
 pub fn init_test_idt() {
     TEST_IDT.load();
-}
\ No newline at end of file
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail, SelectorErrorCode};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_segment::stack_segment_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+#[inline(never)]
+fn trigger() {
+    // In long mode, a non-canonical address formed from RSP raises #SS
+    // rather than #GP. Flipping bit 47 of RSP makes it non-canonical
+    // whichever half of the address space the stack is in.
+    unsafe {
+        asm!(
+            "mov {0}, rsp",
+            "btc {0}, 47",
+            "sub {0}, rsp",
+            "mov {0}, [rsp + {0}]",
+            out(reg) _,
+            options(readonly, nostack),
+        );
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::StackSegmentFault,
+            header: "EXCEPTION: STACK-SEGMENT FAULT (#SS, vector 12)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: Some(0), // a non-canonical access has a zero error code
+            detail: |detail| detail == ExceptionDetail::Selector(SelectorErrorCode(0)),
+            report_contains: &["selector:            none"],
+        },
+    )
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kurogane_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::time;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kurogane_os::init(boot_info);
+    // Against PIT channel 2, which counts without the timer interrupt, so a
+    // tick rate that's off doesn't throw off the TSC as well.
+    time::tsc::calibrate();
+    test_main();
+    loop {}
+}
+
+// The TSC runs at a constant rate, so counting TSC cycles across a number
+// of ticks shows whether the ticks come at the rate the PIT was programmed
+// for.
+fn tsc_cycles_per_tick(ticks: u64) -> u64 {
+    // Start right at a tick boundary.
+    time::sleep_ticks(1);
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    time::sleep_ticks(ticks);
+    let end = unsafe { core::arch::x86_64::_rdtsc() };
+    (end - start) / ticks
+}
+
+fn assert_ratio(measured: u64, reference: u64, expected: u64) {
+    // QEMU without KVM doesn't keep perfect time, so allow 25%.
+    let ratio_percent = measured * 100 / reference;
+    assert!(
+        ratio_percent >= expected * 75 && ratio_percent <= expected * 125,
+        "expected a ratio of {}, measured {}.{:02}",
+        expected,
+        ratio_percent / 100,
+        ratio_percent % 100
+    );
+}
+
+#[test_case]
+fn a_thousand_ticks_take_a_second() {
+    assert_eq!(time::set_tick_frequency(time::DEFAULT_TICK_HZ), time::DEFAULT_TICK_HZ);
+    let ticks = 1000;
+    let cycles = tsc_cycles_per_tick(ticks) * ticks;
+    let nanos = time::tsc::cycles_to_nanos(cycles).expect("the TSC was not calibrated");
+    let expected = ticks * 1_000_000_000 / u64::from(time::DEFAULT_TICK_HZ);
+    assert_ratio(nanos, expected, 1);
+}
+
+#[test_case]
+fn ticks_are_evenly_spaced() {
+    let short = tsc_cycles_per_tick(10);
+    let long = tsc_cycles_per_tick(50);
+    assert_ratio(long, short, 1);
+}
+
+#[test_case]
+fn tick_rate_follows_the_frequency() {
+    assert_eq!(time::set_tick_frequency(1000), 1000);
+    let fast = tsc_cycles_per_tick(20);
+    assert_eq!(time::set_tick_frequency(100), 100);
+    let slow = tsc_cycles_per_tick(20);
+    time::set_tick_frequency(time::DEFAULT_TICK_HZ);
+    assert_ratio(slow, fast, 10);
+}
+
+#[test_case]
+fn monotonic_nanos_survive_a_frequency_change() {
+    let before = time::monotonic_nanos();
+    time::set_tick_frequency(250);
+    time::sleep_ticks(5);
+    let after = time::monotonic_nanos();
+    time::set_tick_frequency(time::DEFAULT_TICK_HZ);
+    // 5 ticks at 250 Hz are 20 ms.
+    assert!(after - before >= 19_000_000, "only {} ns passed", after - before);
+    assert!(time::monotonic_nanos() >= after);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::test_panic_handler(info)
+}
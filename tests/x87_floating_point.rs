@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kurogane_os::exceptions::{Exception, ExceptionDetail};
+use kurogane_os::{code_of, serial_print, ExpectedException};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("x87_floating_point::x87_floating_point_report...\t");
+    kurogane_os::init(boot_info);
+
+    trigger();
+
+    kurogane_os::exception_test_did_not_fault()
+}
+
+const FPU_UNMASK_INVALID_AND_ZERO_DIVIDE: u16 = 0x037f & !0b101;
+
+#[inline(never)]
+fn trigger() {
+    // With CR0.NE set, x87 errors are reported as #MF at the next waiting
+    // instruction instead of through the legacy IRQ 13. 0.0 / 0.0 is an
+    // invalid operation once that exception is unmasked.
+    unsafe {
+        asm!(
+            "mov rax, cr0",
+            "and rax, -5",
+            "or rax, 0x22",
+            "mov cr0, rax",
+            "fninit",
+            "fldcw [{0}]",
+            "fldz",
+            "fldz",
+            "fdiv st(0), st(1)",
+            "fwait",
+            in(reg) &FPU_UNMASK_INVALID_AND_ZERO_DIVIDE,
+            out("rax") _,
+            options(nostack),
+        );
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kurogane_os::exception_test_panic_handler(
+        info,
+        &ExpectedException {
+            exception: Exception::X87FloatingPoint,
+            header: "EXCEPTION: X87 FLOATING-POINT ERROR (#MF, vector 16)",
+            instruction_pointer: code_of(trigger),
+            instruction: None,
+            error_code: None,
+            detail: |detail| match detail {
+                ExceptionDetail::X87 { status_word } => status_word & 0b1 != 0, // invalid operation
+                _ => false,
+            },
+            report_contains: &["fpu status word:", "(invalid operation)"],
+        },
+    )
+}